@@ -17,4 +17,11 @@ fn eval(s: String) -> String {
 #[ic_cdk::init]
 fn init_fn() {
     inject_shims();
+    rng::schedule();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade_fn() {
+    inject_shims();
+    rng::schedule();
 }
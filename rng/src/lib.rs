@@ -0,0 +1,208 @@
+//! Random bytes for the WASI `random_get` shims of the canisters in this
+//! repository, from a SHA-256 generator seeded by the management canister's
+//! `raw_rand`.
+
+use std::{cell::RefCell, collections::hash_map::RandomState, fmt, slice, time::Duration};
+
+use sha2::{Digest, Sha256};
+use wasi_shim::wasi::{Errno, Size, ERRNO_AGAIN, ERRNO_SUCCESS};
+
+// Bounds how much output is derived from a single seed
+const RESEED_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Returned until `raw_rand` has answered since the canister was installed or
+/// upgraded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unseeded;
+
+impl fmt::Display for Unseeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "random bytes are not available until the generator is seeded"
+        )
+    }
+}
+
+impl std::error::Error for Unseeded {}
+
+/// SHA-256 in counter mode. The key is replaced after every request, so
+/// earlier output cannot be recovered from the current state.
+struct Rng {
+    key: [u8; 32],
+    counter: u64,
+}
+
+impl Rng {
+    fn new(seed: &[u8]) -> Self {
+        Self {
+            key: Sha256::digest(seed).into(),
+            counter: 0,
+        }
+    }
+
+    fn mix(&mut self, input: &[u8]) {
+        let mut h = Sha256::new();
+        h.update(self.key);
+        h.update(input);
+
+        self.key = h.finalize().into();
+    }
+
+    fn block(&mut self) -> [u8; 32] {
+        let mut h = Sha256::new();
+        h.update(self.key);
+        h.update(self.counter.to_le_bytes());
+
+        self.counter += 1;
+
+        h.finalize().into()
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(32) {
+            let b = self.block();
+            chunk.copy_from_slice(&b[..chunk.len()]);
+        }
+
+        self.key = self.block();
+    }
+}
+
+thread_local! {
+    static RNG: RefCell<Option<Rng>> = const { RefCell::new(None) };
+}
+
+/// Fills `buf` with random bytes.
+///
+/// State changes of query calls are discarded, so the state a query draws
+/// from is the one the next update call starts with. It is mixed with values
+/// of the current message first, so the two draw different bytes, and the
+/// bytes a query returns reveal nothing of the state itself.
+pub fn fill(buf: &mut [u8]) -> Result<(), Unseeded> {
+    RNG.with(|r| {
+        let mut r = r.borrow_mut();
+        let r = r.as_mut().ok_or(Unseeded)?;
+
+        r.mix(&message_input());
+        r.fill(buf);
+
+        Ok(())
+    })
+}
+
+/// Registers `random_get` with the WASI shim.
+///
+/// std draws the keys of every `HashMap` from `random_get` the first time
+/// one is created, which happens long before `raw_rand` can answer. Those
+/// keys are drawn here from `bootstrap_random_get` instead, so hash maps work
+/// from the start; they only guard against collision attacks.
+pub fn inject_shim() {
+    unsafe {
+        wasi_shim::core::random::set::random_get(bootstrap_random_get);
+    }
+
+    let _ = RandomState::new();
+
+    unsafe {
+        wasi_shim::core::random::set::random_get(random_get);
+    }
+}
+
+// `random_get` backed by the generator, failing with `ERRNO_AGAIN` until it
+// is seeded
+fn random_get(buf: *mut u8, buf_len: Size) -> Errno {
+    if buf_len == 0 {
+        return ERRNO_SUCCESS;
+    }
+
+    let buf = unsafe { slice::from_raw_parts_mut(buf, buf_len) };
+
+    match fill(buf) {
+        Ok(()) => ERRNO_SUCCESS,
+        Err(Unseeded) => ERRNO_AGAIN,
+    }
+}
+
+// Predictable but always available, which is enough for the HashMap keys
+fn bootstrap_random_get(buf: *mut u8, buf_len: Size) -> Errno {
+    if buf_len == 0 {
+        return ERRNO_SUCCESS;
+    }
+
+    let buf = unsafe { slice::from_raw_parts_mut(buf, buf_len) };
+
+    let mut seed = ic_cdk::api::id().as_slice().to_vec();
+    seed.extend(message_input());
+
+    Rng::new(&seed).fill(buf);
+
+    ERRNO_SUCCESS
+}
+
+/// Seeds the generator from `raw_rand` as soon as possible and again every
+/// `RESEED_INTERVAL`. Timers and the generator do not survive upgrades, so
+/// this runs in `post_upgrade` as well as `init`.
+pub fn schedule() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(reseed()));
+    ic_cdk_timers::set_timer_interval(RESEED_INTERVAL, || ic_cdk::spawn(reseed()));
+}
+
+async fn reseed() {
+    let seed = match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((v,)) => v,
+        Err((code, msg)) => {
+            // Keeps the current state until the next attempt
+            ic_cdk::println!("failed to reseed: {code:?} {msg}");
+            return;
+        }
+    };
+
+    RNG.with(|r| match &mut *r.borrow_mut() {
+        Some(r) => r.mix(&seed),
+        r @ None => *r = Some(Rng::new(&seed)),
+    });
+}
+
+// Values that differ between messages and are available in every kind of call
+fn message_input() -> Vec<u8> {
+    [
+        ic_cdk::api::time(),
+        ic_cdk::api::canister_version(),
+        ic_cdk::api::performance_counter(0),
+    ]
+    .iter()
+    .flat_map(|v| v.to_le_bytes())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_key_after_each_request() {
+        let mut r = Rng::new(b"seed");
+
+        let (mut a, mut b) = ([0; 40], [0; 40]);
+        r.fill(&mut a);
+        r.fill(&mut b);
+
+        assert_ne!(a, b);
+        assert_ne!(a[..32], a[32..]);
+    }
+
+    #[test]
+    fn message_input_changes_output() {
+        let (mut r, mut s) = (Rng::new(b"seed"), Rng::new(b"seed"));
+
+        r.mix(b"query");
+        s.mix(b"update");
+
+        let (mut a, mut b) = ([0; 16], [0; 16]);
+        r.fill(&mut a);
+        s.fill(&mut b);
+
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,78 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::Deserialize;
+
+use crate::{error::Error, ROLES};
+
+/// Roles are ordered, each one implies the permissions of those below it.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Reader,
+    Writer,
+    Admin,
+}
+
+impl Storable for Role {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(vec![*self as u8])
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        match bytes[0] {
+            0 => Role::Reader,
+            1 => Role::Writer,
+            2 => Role::Admin,
+            v => panic!("invalid role {v}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1,
+        is_fixed_size: true,
+    };
+}
+
+/// Controllers and the canister itself are always admins, everyone else gets
+/// what was granted to them.
+pub(crate) fn role_of(p: &Principal) -> Option<Role> {
+    if *p == ic_cdk::id() || ic_cdk::api::is_controller(p) {
+        return Some(Role::Admin);
+    }
+
+    ROLES.with(|m| m.borrow().get(p))
+}
+
+pub(crate) fn authorize(required: Role) -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+
+    match role_of(&caller) {
+        Some(r) if r >= required => Ok(caller),
+        _ => Err(Error::Unauthorized { required }),
+    }
+}
+
+/// Restricts a method to controllers, for operations that hand out the whole
+/// database regardless of granted roles.
+pub(crate) fn authorize_controller() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(Error::ControllerRequired);
+    }
+
+    Ok(caller)
+}
+
+pub(crate) fn grant(p: Principal, r: Role) {
+    ROLES.with(|m| m.borrow_mut().insert(p, r));
+}
+
+pub(crate) fn revoke(p: &Principal) -> Option<Role> {
+    ROLES.with(|m| m.borrow_mut().remove(p))
+}
+
+pub(crate) fn list() -> Vec<(Principal, Role)> {
+    ROLES.with(|m| m.borrow().iter().collect())
+}
@@ -0,0 +1,343 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    rw::{remove, rename, write_chunks},
+    snapshot::Snapshot,
+    BACKUP, BACKUP_CHUNKS, BACKUP_HEADER, FILESYSTEM,
+};
+
+// Chunks are kept well below the heap and message limits so that backing up
+// or restoring a large database never needs more than one chunk in memory.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// Where a backup is written before it is checked and moved into place
+const RESTORE_PATH: &str = "restore.sqlite3";
+
+// Chunks of a new backup are written next to those of the current one, under
+// keys offset by this much, so the current backup stays intact until the new
+// one is complete.
+const SLOT_SIZE: u64 = 1 << 40;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub(crate) struct Header {
+    pub(crate) size: u64,
+    pub(crate) chunks: u64,
+    pub(crate) sha256: Vec<u8>,
+
+    /// Key of the first chunk, absent in backups taken before slots existed.
+    pub(crate) base: Option<u64>,
+}
+
+impl Storable for Header {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode backup header"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to decode backup header")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Backup in progress, passed back to `step` to continue it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub(crate) struct Cursor {
+    base: u64,
+    snapshot: Snapshot,
+}
+
+impl Cursor {
+    /// Chunks copied so far and the total.
+    pub(crate) fn progress(&self) -> (u64, u64) {
+        (
+            self.snapshot.copied.div_ceil(CHUNK_SIZE as u64),
+            self.snapshot.size.div_ceil(CHUNK_SIZE as u64),
+        )
+    }
+}
+
+pub(crate) enum Step {
+    Partial(Cursor),
+    Done(Header),
+}
+
+/// Copies up to `max_chunks` chunks of `path` into stable memory, starting a
+/// new backup if `cursor` is `None`. The previous backup is only replaced
+/// once the new one is complete. A cursor must not be resumed after an
+/// upgrade.
+pub(crate) fn step(path: &str, cursor: Option<Cursor>, max_chunks: u64) -> Result<Step, Error> {
+    let current = BACKUP_HEADER
+        .with(|m| m.borrow().get(&()))
+        .and_then(|h| h.base);
+
+    // A backup completed since the cursor was handed out may have taken over
+    // its slot, in which case it starts over in the other one
+    let mut c = match cursor {
+        Some(v) if Some(v.base) != current => v,
+        _ => Cursor {
+            base: match current {
+                Some(0) | None => SLOT_SIZE,
+                Some(_) => 0,
+            },
+            snapshot: Snapshot::start(path)?,
+        },
+    };
+
+    let base = c.base;
+
+    c.snapshot
+        .step(path, CHUNK_SIZE, max_chunks, |offset, bs| {
+            BACKUP_CHUNKS.with(|m| {
+                m.borrow_mut().insert(
+                    base + offset / CHUNK_SIZE as u64, // k
+                    bs.into(),                         // v
+                )
+            });
+
+            Ok(())
+        })?;
+
+    if !c.snapshot.done() {
+        return Ok(Step::Partial(c));
+    }
+
+    let hdr = Header {
+        size: c.snapshot.size,
+        chunks: c.snapshot.size.div_ceil(CHUNK_SIZE as u64),
+        sha256: c.snapshot.sha256(),
+        base: Some(base),
+    };
+
+    BACKUP_HEADER.with(|m| m.borrow_mut().insert((), hdr.clone()));
+
+    // Drop the previous backup, and chunks left over from a larger attempt
+    BACKUP_CHUNKS.with(|m| {
+        let mut m = m.borrow_mut();
+
+        let stale: Vec<u64> = m
+            .keys()
+            .filter(|k| *k < base || *k >= base + hdr.chunks)
+            .collect();
+
+        for k in stale {
+            m.remove(&k);
+        }
+    });
+
+    Ok(Step::Done(hdr))
+}
+
+/// Backs up `path` in full within the current message. This is bounded by the
+/// message's instruction limit, so it only suits databases that can be read
+/// through in one go; use `step` for anything larger.
+#[cfg_attr(feature = "stable-storage", allow(dead_code))]
+pub(crate) fn save(path: &str) -> Result<Header, Error> {
+    match step(path, None, u64::MAX)? {
+        Step::Done(hdr) => Ok(hdr),
+        Step::Partial(_) => unreachable!("an unbounded step always completes"),
+    }
+}
+
+/// Restores `path` from the chunked backup, falling back to the legacy
+/// single-value backup. Returns `false` if no backup was found. The backup is
+/// written to a staging file and only moved over `path` once its size and
+/// checksum match the header.
+pub(crate) fn restore(path: &str) -> Result<bool, Error> {
+    let Some(hdr) = BACKUP_HEADER.with(|m| m.borrow().get(&())) else {
+        return restore_legacy(path);
+    };
+
+    let base = hdr.base.unwrap_or(0);
+
+    let mut h = Sha256::new();
+
+    let res = FILESYSTEM.with(|fs| {
+        write_chunks(
+            fs.borrow_mut(), // fs
+            RESTORE_PATH,    // path
+            (0..hdr.chunks).map(|idx| {
+                let Some(bs) = BACKUP_CHUNKS.with(|m| m.borrow().get(&(base + idx))) else {
+                    return Err(Error::Validation(format!(
+                        "backup is missing chunk {idx} of {}",
+                        hdr.chunks
+                    )));
+                };
+
+                h.update(&bs);
+
+                Ok(bs)
+            }),
+        )
+    });
+
+    let checked = res.and_then(|size| {
+        if size != hdr.size {
+            return Err(Error::Validation(format!(
+                "backup size mismatch: expected {}, got {size}",
+                hdr.size
+            )));
+        }
+
+        if h.finalize().as_slice() != hdr.sha256 {
+            return Err(Error::Validation("backup checksum mismatch".into()));
+        }
+
+        Ok(())
+    });
+
+    if let Err(err) = checked {
+        let _ = FILESYSTEM.with(|fs| remove(fs.borrow_mut(), RESTORE_PATH));
+        return Err(err);
+    }
+
+    FILESYSTEM.with(|fs| remove(fs.borrow_mut(), path))?;
+    FILESYSTEM.with(|fs| rename(fs.borrow_mut(), RESTORE_PATH, path))?;
+
+    Ok(true)
+}
+
+fn restore_legacy(path: &str) -> Result<bool, Error> {
+    let Some(bs) = BACKUP.with(|m| m.borrow().get(&())) else {
+        return Ok(false);
+    };
+
+    FILESYSTEM.with(|fs| {
+        write_chunks(
+            fs.borrow_mut(), // fs
+            path,            // path
+            [Ok(bs)],        // chunks
+        )
+    })?;
+
+    Ok(true)
+}
+
+/// Releases the stable memory held by all backups.
+pub(crate) fn clear() {
+    BACKUP.with(|m| m.borrow_mut().remove(&()));
+    BACKUP_HEADER.with(|m| m.borrow_mut().remove(&()));
+    BACKUP_CHUNKS.with(|m| m.borrow_mut().clear_new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rw::{read_range, write_range};
+
+    const PATH: &str = "db.sqlite3";
+
+    fn write(bs: &[u8]) {
+        FILESYSTEM
+            .with(|fs| write_chunks(fs.borrow_mut(), PATH, [Ok(bs.to_vec())]))
+            .unwrap();
+    }
+
+    fn read() -> Vec<u8> {
+        FILESYSTEM
+            .with(|fs| read_range(fs.borrow_mut(), PATH, 0, usize::MAX))
+            .unwrap()
+    }
+
+    fn data(chunks: f64, seed: u8) -> Vec<u8> {
+        let n = (chunks * CHUNK_SIZE as f64) as usize;
+
+        (0..n).map(|i| (i / 7) as u8 ^ seed).collect()
+    }
+
+    fn chunk_keys() -> Vec<u64> {
+        BACKUP_CHUNKS.with(|m| m.borrow().keys().collect())
+    }
+
+    #[test]
+    fn resumes_and_restarts_interrupted_backup() {
+        let db = data(3.5, 1);
+        write(&db);
+
+        let Step::Partial(c) = step(PATH, None, 1).unwrap() else {
+            panic!("backup completed in one chunk");
+        };
+        assert_eq!(c.progress(), (1, 4));
+
+        // A write in between starts the copy over
+        let mut changed = db.clone();
+        changed[24] ^= 0xff;
+        FILESYSTEM
+            .with(|fs| write_range(fs.borrow_mut(), PATH, 24, &changed[24..25]))
+            .unwrap();
+
+        let Step::Partial(c) = step(PATH, Some(c), 2).unwrap() else {
+            panic!("backup completed early");
+        };
+        assert_eq!(c.progress(), (2, 4));
+
+        let Step::Done(hdr) = step(PATH, Some(c), 10).unwrap() else {
+            panic!("backup did not complete");
+        };
+        assert_eq!((hdr.size, hdr.chunks), (changed.len() as u64, 4));
+
+        write(b"lost");
+        assert!(restore(PATH).unwrap());
+        assert_eq!(read(), changed);
+    }
+
+    #[test]
+    fn restores_after_shorter_backup() {
+        write(&data(4.0, 1));
+        let first = save(PATH).unwrap();
+
+        let db = data(1.5, 2);
+        write(&db);
+        let second = save(PATH).unwrap();
+
+        // The second backup went to the other slot and dropped the first
+        assert_ne!(first.base, second.base);
+
+        let base = second.base.unwrap();
+        assert_eq!(chunk_keys(), vec![base, base + 1]);
+
+        write(b"lost");
+        assert!(restore(PATH).unwrap());
+        assert_eq!(read(), db);
+    }
+
+    #[test]
+    fn rejects_missing_chunk() {
+        let db = data(2.5, 1);
+        write(&db);
+        let hdr = save(PATH).unwrap();
+
+        BACKUP_CHUNKS.with(|m| m.borrow_mut().remove(&(hdr.base.unwrap() + 1)));
+
+        let err = restore(PATH).unwrap_err();
+        assert!(matches!(err, Error::Validation(v) if v.contains("missing chunk 1")));
+
+        // The database is left alone
+        assert_eq!(read(), db);
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let db = data(2.5, 1);
+        write(&db);
+        let hdr = save(PATH).unwrap();
+
+        let key = hdr.base.unwrap();
+        BACKUP_CHUNKS.with(|m| {
+            let mut m = m.borrow_mut();
+            let mut bs = m.get(&key).unwrap();
+            bs[0] ^= 1;
+            m.insert(key, bs);
+        });
+
+        let err = restore(PATH).unwrap_err();
+        assert!(matches!(err, Error::Validation(v) if v.contains("checksum")));
+        assert_eq!(read(), db);
+    }
+}
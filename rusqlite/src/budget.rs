@@ -0,0 +1,52 @@
+use std::cell::Cell;
+
+// Kept below the replicated and non-replicated message limits, leaving room
+// to encode the reply after SQLite gives up.
+const DEFAULT_UPDATE_BUDGET: u64 = 35_000_000_000;
+const DEFAULT_QUERY_BUDGET: u64 = 4_500_000_000;
+
+/// Number of SQLite VM instructions between two budget checks.
+pub(crate) const CHECK_INTERVAL: i32 = 1000;
+
+thread_local! {
+    static BUDGET: Cell<Option<u64>> = const { Cell::new(None) };
+
+    // Instructions used when the budget was exceeded, picked up when the
+    // interrupted statement's error is converted
+    static EXCEEDED: Cell<Option<(u64, u64)>> = const { Cell::new(None) };
+}
+
+pub(crate) fn set(budget: Option<u64>) {
+    BUDGET.with(|b| b.set(budget));
+}
+
+fn get() -> u64 {
+    BUDGET.with(|b| b.get()).unwrap_or_else(|| {
+        if ic_cdk::api::in_replicated_execution() {
+            DEFAULT_UPDATE_BUDGET
+        } else {
+            DEFAULT_QUERY_BUDGET
+        }
+    })
+}
+
+/// Progress handler installed on `CONN`, returning `true` interrupts the
+/// running statement.
+pub(crate) fn progress_handler() -> bool {
+    let budget = get();
+    let used = ic_cdk::api::performance_counter(0);
+
+    if used < budget {
+        return false;
+    }
+
+    EXCEEDED.with(|e| e.set(Some((used, budget))));
+
+    true
+}
+
+/// Returns the instructions used and the budget if the last interrupt was
+/// caused by the budget being exceeded.
+pub(crate) fn take_exceeded() -> Option<(u64, u64)> {
+    EXCEEDED.with(|e| e.take())
+}
@@ -0,0 +1,421 @@
+use candid::CandidType;
+use rusqlite::{params_from_iter, types::Value, CachedStatement, Connection};
+use serde::Deserialize;
+
+use crate::{conv::quote_ident, error::Error, policy};
+
+// Keeps the reply small when most of a large upload is rejected
+const MAX_REPORTED_ERRORS: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum Format {
+    /// Comma-separated values, the first record naming the columns.
+    Csv,
+
+    /// One JSON object per line, keys naming the columns.
+    Ndjson,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RowError {
+    /// 1-based record number, not counting the CSV header.
+    pub row: u64,
+    pub message: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BulkResult {
+    pub inserted: u64,
+    pub failed: u64,
+
+    /// The first `MAX_REPORTED_ERRORS` failures.
+    pub errors: Vec<RowError>,
+}
+
+/// Inserts every record of `data` into `table` in a single transaction.
+/// Records that fail to parse or insert are reported and skipped; anything
+/// that would fail every record (e.g. a denied table) aborts the import.
+pub(crate) fn insert(
+    conn: &mut Connection,
+    table: &str,
+    format: Format,
+    data: &str,
+) -> Result<BulkResult, Error> {
+    let tx = policy::transaction(conn)?;
+
+    let mut out = BulkResult {
+        inserted: 0,
+        failed: 0,
+        errors: vec![],
+    };
+
+    let mut report = |row: u64, message: String| {
+        out.failed += 1;
+
+        if out.errors.len() < MAX_REPORTED_ERRORS {
+            out.errors.push(RowError { row, message });
+        }
+    };
+
+    let mut inserted = 0;
+
+    match format {
+        Format::Csv => {
+            let mut records = csv::Reader::new(data);
+
+            let header = match records.next() {
+                Some(Ok(v)) => v,
+                Some(Err(err)) => return Err(Error::Validation(format!("header: {err}"))),
+                None => return Err(Error::Validation("missing header".into())),
+            };
+
+            let columns: Vec<String> = header.into_iter().map(Option::unwrap_or_default).collect();
+            let mut stmt = prepare(&tx, table, &columns)?;
+
+            for (row, rec) in (1..).zip(records) {
+                let res = rec.map_err(Error::Validation).and_then(|fields| {
+                    if fields.len() != columns.len() {
+                        return Err(Error::Validation(format!(
+                            "expected {} fields, found {}",
+                            columns.len(),
+                            fields.len()
+                        )));
+                    }
+
+                    let vs = fields
+                        .into_iter()
+                        .map(|v| v.map_or(Value::Null, Value::Text));
+                    Ok(stmt.execute(params_from_iter(vs))?)
+                });
+
+                match res {
+                    Ok(_) => inserted += 1,
+                    Err(err) => report(row, fatal(err)?),
+                }
+            }
+        }
+
+        Format::Ndjson => {
+            let lines = data.lines().filter(|l| !l.trim().is_empty());
+
+            for (row, line) in (1..).zip(lines) {
+                let res = json::object(line)
+                    .map_err(Error::Validation)
+                    .and_then(|obj| {
+                        let (keys, vs): (Vec<_>, Vec<_>) = obj.into_iter().unzip();
+
+                        // Objects may omit keys, so the statement cache holds one
+                        // insert per distinct key set
+                        let mut stmt = prepare(&tx, table, &keys)?;
+
+                        Ok(stmt.execute(params_from_iter(vs))?)
+                    });
+
+                match res {
+                    Ok(_) => inserted += 1,
+                    Err(err) => report(row, fatal(err)?),
+                }
+            }
+        }
+    }
+
+    out.inserted = inserted;
+    tx.commit()?;
+
+    Ok(out)
+}
+
+fn prepare<'a>(
+    conn: &'a Connection,
+    table: &str,
+    columns: &[String],
+) -> Result<CachedStatement<'a>, Error> {
+    if columns.is_empty() {
+        return Err(Error::Validation("no columns".into()));
+    }
+
+    let names: Vec<_> = columns.iter().map(|c| quote_ident(c)).collect();
+    let slots: Vec<_> = (1..=columns.len()).map(|i| format!("?{i}")).collect();
+
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        quote_ident(table),
+        names.join(", "),
+        slots.join(", "),
+    );
+
+    Ok(policy::prepare_cached(conn, &sql)?)
+}
+
+// Per-record failures are reported; denials and budget exhaustion abort the
+// whole import so it is rolled back.
+fn fatal(err: Error) -> Result<String, Error> {
+    match err {
+        Error::Denied(_) | Error::InstructionBudgetExceeded { .. } => Err(err),
+        err => Ok(err.to_string()),
+    }
+}
+
+mod csv {
+    use std::{iter::Peekable, str::Chars};
+
+    /// Splits RFC 4180 records. Unquoted empty fields are `None` (NULL),
+    /// quoted ones are empty strings. A malformed record is reported and
+    /// reading resumes at the next line.
+    pub(super) struct Reader<'a> {
+        chars: Peekable<Chars<'a>>,
+    }
+
+    impl<'a> Reader<'a> {
+        pub(super) fn new(data: &'a str) -> Self {
+            Self {
+                chars: data.chars().peekable(),
+            }
+        }
+
+        fn record(&mut self) -> Result<Vec<Option<String>>, String> {
+            let mut fields = vec![];
+
+            loop {
+                fields.push(self.field()?);
+
+                match self.chars.next() {
+                    Some(',') => continue,
+                    Some('\r') if self.chars.peek() == Some(&'\n') => {
+                        self.chars.next();
+                        break;
+                    }
+                    Some('\n') | None => break,
+                    Some(c) => return Err(format!("unexpected {c:?} after quoted field")),
+                }
+            }
+
+            Ok(fields)
+        }
+
+        // Leaves the delimiter that ended the field unconsumed
+        fn field(&mut self) -> Result<Option<String>, String> {
+            if self.chars.peek() != Some(&'"') {
+                let mut v = String::new();
+
+                while let Some(&c) = self.chars.peek() {
+                    match c {
+                        ',' | '\n' => break,
+                        '\r' => {
+                            let mut ahead = self.chars.clone();
+                            ahead.next();
+                            if ahead.peek() == Some(&'\n') {
+                                break;
+                            }
+                        }
+                        '"' => return Err("unexpected quote in unquoted field".into()),
+                        _ => {}
+                    }
+
+                    v.push(c);
+                    self.chars.next();
+                }
+
+                return Ok((!v.is_empty()).then_some(v));
+            }
+
+            self.chars.next();
+            let mut v = String::new();
+
+            loop {
+                match self.chars.next() {
+                    Some('"') if self.chars.peek() == Some(&'"') => {
+                        self.chars.next();
+                        v.push('"');
+                    }
+                    Some('"') => return Ok(Some(v)),
+                    Some(c) => v.push(c),
+                    None => return Err("unterminated quoted field".into()),
+                }
+            }
+        }
+
+        fn skip_line(&mut self) {
+            for c in self.chars.by_ref() {
+                if c == '\n' {
+                    break;
+                }
+            }
+        }
+    }
+
+    impl Iterator for Reader<'_> {
+        type Item = Result<Vec<Option<String>>, String>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            // Blank lines between records are ignored
+            while let Some('\n' | '\r') = self.chars.peek() {
+                self.chars.next();
+            }
+
+            self.chars.peek()?;
+
+            let rec = self.record();
+            if rec.is_err() {
+                self.skip_line();
+            }
+
+            Some(rec)
+        }
+    }
+}
+
+mod json {
+    use rusqlite::types::Value;
+    use serde_json::Map;
+
+    /// Parses a single flat JSON object. Strings, numbers, booleans and
+    /// `null` map to SQLite values; nested arrays and objects are rejected.
+    pub(super) fn object(s: &str) -> Result<Vec<(String, Value)>, String> {
+        let obj: Map<String, serde_json::Value> =
+            serde_json::from_str(s).map_err(|err| err.to_string())?;
+
+        obj.into_iter().map(|(k, v)| Ok((k, value(v)?))).collect()
+    }
+
+    fn value(v: serde_json::Value) -> Result<Value, String> {
+        match v {
+            serde_json::Value::Null => Ok(Value::Null),
+            serde_json::Value::Bool(v) => Ok(Value::Integer(v.into())),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(v) => Ok(Value::Integer(v)),
+                None => n
+                    .as_f64()
+                    .map(Value::Real)
+                    .ok_or_else(|| format!("invalid number {n}")),
+            },
+            serde_json::Value::String(v) => Ok(Value::Text(v)),
+            serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+                Err("nested values are not supported".into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Role;
+
+    fn open(schema: &str) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute_batch("CREATE TABLE t (a TEXT, b TEXT, n INTEGER CHECK (n > 0));")
+            .unwrap();
+        conn.execute_batch(schema).unwrap();
+        conn.authorizer(Some(policy::authorizer));
+
+        conn
+    }
+
+    fn run(
+        conn: &mut Connection,
+        role: Role,
+        format: Format,
+        data: &str,
+    ) -> Result<BulkResult, Error> {
+        policy::with_role(role, || insert(conn, "t", format, data))
+    }
+
+    fn rows(conn: &Connection) -> Vec<(Option<String>, Option<String>, Option<i64>)> {
+        policy::with_role(Role::Admin, || {
+            let mut stmt = conn
+                .prepare("SELECT a, b, n FROM t ORDER BY rowid")
+                .unwrap();
+
+            stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        })
+    }
+
+    fn text(a: &str, b: Option<&str>, n: i64) -> (Option<String>, Option<String>, Option<i64>) {
+        (Some(a.into()), b.map(Into::into), Some(n))
+    }
+
+    #[test]
+    fn reads_quoted_csv_fields() {
+        let mut conn = open("");
+
+        let data = "a,b,n\r\n\"x, \"\"y\"\"\",\"\",1\r\n\"two\r\nlines\",,2\n\nplain,z,3";
+        let res = run(&mut conn, Role::Writer, Format::Csv, data).unwrap();
+
+        assert_eq!((res.inserted, res.failed), (3, 0));
+        assert_eq!(
+            rows(&conn),
+            [
+                text("x, \"y\"", Some(""), 1),
+                text("two\r\nlines", None, 2),
+                text("plain", Some("z"), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_bad_csv_records() {
+        let mut conn = open("");
+
+        // Too few fields, too many, a constraint violation and a stray quote
+        let data = "a,b,n\nx,y\nx,y,1,2\nx,y,0\nx\"y,z,1\nok,ok,1\n";
+        let res = run(&mut conn, Role::Writer, Format::Csv, data).unwrap();
+
+        assert_eq!((res.inserted, res.failed), (1, 4));
+        assert_eq!(
+            res.errors.iter().map(|e| e.row).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(rows(&conn), [text("ok", Some("ok"), 1)]);
+    }
+
+    #[test]
+    fn reads_ndjson() {
+        let mut conn = open("");
+
+        let data =
+            "{\"a\": \"x\\ny\", \"n\": 1}\r\n\n{\"n\": 2, \"a\": \"\\u00e9\", \"b\": null}\n\
+                    {\"a\": [1], \"n\": 3}\n{\"a\": \"x\"\n{\"a\": true, \"n\": 4}";
+        let res = run(&mut conn, Role::Writer, Format::Ndjson, data).unwrap();
+
+        assert_eq!((res.inserted, res.failed), (3, 2));
+        assert_eq!(res.errors.iter().map(|e| e.row).collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(
+            rows(&conn),
+            [
+                text("x\ny", None, 1),
+                text("\u{e9}", None, 2),
+                text("1", None, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn rolls_back_when_denied() {
+        // Every insert fires a trigger writing to a table reserved to admins
+        let mut conn = open(
+            "CREATE TABLE _log (a TEXT);
+             CREATE TRIGGER t_log AFTER INSERT ON t BEGIN INSERT INTO _log VALUES (new.a); END;",
+        );
+
+        for (format, data) in [
+            (Format::Csv, "a,n\nx,1\ny,2\n"),
+            (
+                Format::Ndjson,
+                "{\"a\": \"x\", \"n\": 1}\n{\"a\": \"y\", \"n\": 2}",
+            ),
+        ] {
+            let err = run(&mut conn, Role::Writer, format, data).unwrap_err();
+
+            assert!(matches!(err, Error::Denied(_)), "{err:?}");
+            assert!(rows(&conn).is_empty());
+        }
+
+        let err = run(&mut conn, Role::Reader, Format::Csv, "a\nx\n").unwrap_err();
+        assert!(matches!(err, Error::Denied(_)), "{err:?}");
+    }
+}
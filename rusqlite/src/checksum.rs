@@ -0,0 +1,105 @@
+use candid::CandidType;
+use serde::Deserialize;
+use sha2::digest::generic_array::GenericArray;
+
+const BLOCK_SIZE: usize = 64;
+
+// Initial SHA-256 state
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// SHA-256 whose state can be stored, so that a file can be hashed a few
+/// chunks at a time across several messages.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub(crate) struct Checksum {
+    state: Vec<u32>,
+    len: u64,
+
+    // Input not yet making up a whole block
+    buf: Vec<u8>,
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Self {
+            state: H0.to_vec(),
+            len: 0,
+            buf: vec![],
+        }
+    }
+}
+
+impl Checksum {
+    pub(crate) fn update(&mut self, mut bs: &[u8]) {
+        self.len += bs.len() as u64;
+
+        let mut state: [u32; 8] = self.state[..].try_into().expect("invalid checksum state");
+
+        if !self.buf.is_empty() {
+            let n = (BLOCK_SIZE - self.buf.len()).min(bs.len());
+            self.buf.extend_from_slice(&bs[..n]);
+            bs = &bs[n..];
+
+            if self.buf.len() < BLOCK_SIZE {
+                return;
+            }
+
+            compress(&mut state, &self.buf);
+            self.buf.clear();
+        }
+
+        let mut blocks = bs.chunks_exact(BLOCK_SIZE);
+        for b in &mut blocks {
+            compress(&mut state, b);
+        }
+
+        self.buf.extend_from_slice(blocks.remainder());
+        self.state = state.to_vec();
+    }
+
+    pub(crate) fn finalize(&self) -> Vec<u8> {
+        let mut c = self.clone();
+
+        // A single 1 bit, zeros up to 8 bytes short of a block, then the
+        // length in bits
+        let zeros = (BLOCK_SIZE * 2 - 9 - c.buf.len()) % BLOCK_SIZE;
+
+        let mut pad = vec![0x80];
+        pad.resize(1 + zeros, 0);
+        pad.extend_from_slice(&(self.len * 8).to_be_bytes());
+
+        c.update(&pad);
+
+        c.state.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    sha2::compress256(state, &[*GenericArray::from_slice(block)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    #[test]
+    fn matches_sha256() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+
+        for len in [0, 1, 55, 56, 63, 64, 65, 119, 120, 128, 1000] {
+            let want = Sha256::digest(&data[..len]).to_vec();
+
+            for step in [1, 3, 64, 100, 1000] {
+                let mut c = Checksum::default();
+                for bs in data[..len].chunks(step) {
+                    c.update(bs);
+                }
+
+                assert_eq!(c.finalize(), want, "{len} bytes in steps of {step}");
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{storable::Bound, Storable};
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use crate::{budget, error::Error, ENVIRONMENT};
+
+// WAL needs shared memory, which the WASI polyfill does not provide
+const JOURNAL_MODES: &[&str] = &["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "OFF"];
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    /// DDL statements run once, after the built-in migrations. Defaults to
+    /// the `persons` example table, which `seed` then populates.
+    pub schema: Vec<String>,
+
+    /// Statements run once, after `schema`, to populate the database.
+    pub seed: Vec<String>,
+
+    pub settings: Option<Settings>,
+
+    /// Command line and environment seen by the WASI polyfill.
+    pub environment: Option<Environment>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UpgradeArgs {
+    /// Replaces the stored environment, which is kept if absent.
+    pub environment: Option<Environment>,
+}
+
+/// Argument of both install and upgrade, since a canister has one init type.
+/// Each accepts only its own variant.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CanisterArgs {
+    Init(InitArgs),
+    Upgrade(UpgradeArgs),
+}
+
+impl Default for InitArgs {
+    fn default() -> Self {
+        Self {
+            schema: vec![
+                "CREATE TABLE persons (id INTEGER PRIMARY KEY, name TEXT NOT NULL)".into(),
+            ],
+            seed: vec![
+                "INSERT INTO persons (name) VALUES ('Or'), ('Laura'), ('Jacob'), ('Sadie')".into(),
+            ],
+            settings: None,
+            environment: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Settings {
+    pub page_size: Option<u32>,
+    pub journal_mode: Option<String>,
+    pub max_db_size: Option<u64>,
+
+    /// Instructions a single call may spend in SQLite before it is
+    /// interrupted, defaults to just under the message limit.
+    pub instruction_budget: Option<u64>,
+}
+
+impl Storable for Settings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode settings"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to decode settings")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Settings {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if let Some(v) = self.page_size {
+            if !(512..=65536).contains(&v) || !v.is_power_of_two() {
+                return Err(Error::Validation(format!("invalid page size {v}")));
+            }
+        }
+
+        if let Some(v) = &self.journal_mode {
+            if !JOURNAL_MODES.contains(&v.to_uppercase().as_str()) {
+                return Err(Error::Validation(format!("unsupported journal mode {v}")));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the settings to `conn`. Most of these only last for the
+    /// lifetime of the connection, so this runs again after every upgrade.
+    pub(crate) fn apply(&self, conn: &Connection) -> Result<(), Error> {
+        // Only takes effect before the first table is created
+        if let Some(v) = self.page_size {
+            conn.pragma_update(None, "page_size", v)?;
+        }
+
+        if let Some(v) = &self.journal_mode {
+            conn.pragma_update(None, "journal_mode", v)?;
+        }
+
+        budget::set(self.instruction_budget);
+
+        if let Some(v) = self.max_db_size {
+            let page_size: u64 = conn.pragma_query_value(None, "page_size", |r| r.get(0))?;
+            conn.pragma_update(None, "max_page_count", (v / page_size).max(1))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Environment {
+    pub argv: Vec<String>,
+    pub vars: Vec<(String, String)>,
+}
+
+impl Storable for Environment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode environment"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to decode environment")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Environment {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if let Some(v) = self.argv.iter().find(|v| v.contains('\0')) {
+            return Err(Error::Validation(format!("invalid argument {v:?}")));
+        }
+
+        for (k, v) in &self.vars {
+            if k.is_empty() || k.contains(['=', '\0']) || v.contains('\0') {
+                return Err(Error::Validation(format!("invalid variable {k:?}")));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the stored environment. It has to be in place before anything
+    /// reads it, since the C library caches the environment on first use.
+    pub(crate) fn store(self) -> Result<(), Error> {
+        self.validate()?;
+
+        ENVIRONMENT.with(|m| m.borrow_mut().insert((), self));
+
+        Ok(())
+    }
+
+    pub(crate) fn load() -> Self {
+        ENVIRONMENT
+            .with(|m| m.borrow().get(&()))
+            .unwrap_or_default()
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt::Write;
+
 use stable_fs::error::Error;
 
 use wasi_shim::wasi::{
@@ -96,3 +98,15 @@ pub fn error(err: Error) -> Errno {
         Error::ValueTooLargeToBeStoredInDataType => ERRNO_OVERFLOW,
     }
 }
+
+/// Quotes `v` as an SQL identifier.
+pub(crate) fn quote_ident(v: &str) -> String {
+    format!("\"{}\"", v.replace('"', "\"\""))
+}
+
+/// Appends `v` as lowercase hex digits.
+pub(crate) fn hex(s: &mut String, v: &[u8]) {
+    for b in v {
+        let _ = write!(s, "{b:02x}");
+    }
+}
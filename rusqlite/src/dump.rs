@@ -0,0 +1,497 @@
+use std::fmt::Write;
+
+use candid::{CandidType, Decode, Encode};
+use fallible_iterator::FallibleIterator;
+use rusqlite::{types::ValueRef, Batch, Connection};
+use serde::Deserialize;
+
+use crate::{
+    conv::{hex, quote_ident},
+    error::Error,
+    migrations, MAX_PAGE_BYTES,
+};
+
+const MAX_PAGE_STATEMENTS: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DumpPage {
+    pub statements: Vec<String>,
+
+    /// Passed back to `dump` to fetch the statements after this page.
+    pub next: Option<Vec<u8>>,
+}
+
+/// Position in the dump: the table being copied, whether its `CREATE` was
+/// already emitted and how many of its rows. `table == tables.len()` means
+/// the trailing schema objects (indexes, views and triggers), with `offset`
+/// counting those already emitted.
+#[derive(CandidType, Deserialize, Default)]
+struct Cursor {
+    table: u64,
+    created: bool,
+    offset: u64,
+}
+
+struct Page {
+    statements: Vec<String>,
+    bytes: usize,
+}
+
+impl Page {
+    fn has_room(&self, statements: usize, bytes: usize) -> bool {
+        self.statements.len() + statements <= MAX_PAGE_STATEMENTS
+            && self.bytes + bytes <= MAX_PAGE_BYTES
+    }
+
+    // Adds `s` if it fits, failing if it could not even fit on an empty page
+    fn try_push(&mut self, s: String) -> Result<bool, Error> {
+        if s.len() > MAX_PAGE_BYTES {
+            return Err(Error::RowTooLarge {
+                size: s.len() as u64,
+                limit: MAX_PAGE_BYTES as u64,
+            });
+        }
+
+        if !self.has_room(1, s.len()) {
+            return Ok(false);
+        }
+
+        self.bytes += s.len();
+        self.statements.push(s);
+
+        Ok(true)
+    }
+}
+
+/// Reads one page of a script that recreates the database. Tables are
+/// created only if missing and emptied before their rows are inserted, so the
+/// script can be replayed into a canister that already ran its migrations and
+/// init schema.
+pub(crate) fn dump(conn: &Connection, cursor: Option<Vec<u8>>) -> Result<DumpPage, Error> {
+    let mut page = Page {
+        statements: vec![],
+        bytes: 0,
+    };
+
+    let mut c = match cursor {
+        Some(bs) => {
+            Decode!(&bs, Cursor).map_err(|_| Error::Validation("malformed cursor".into()))?
+        }
+        None => {
+            let v = migrations::current_version(conn)?;
+            page.try_push(format!("PRAGMA user_version = {v};"))?;
+
+            Cursor::default()
+        }
+    };
+
+    let next = |c: &Cursor| Some(Encode!(c).expect("failed to encode cursor"));
+
+    let tables = tables(conn)?;
+
+    while (c.table as usize) < tables.len() {
+        let (name, sql) = &tables[c.table as usize];
+
+        if !c.created {
+            let prologue: Vec<_> = sql
+                .iter()
+                .map(|v| format!("{};", if_not_exists(v)))
+                .chain([format!("DELETE FROM {};", quote_ident(name))])
+                .collect();
+
+            // A table's CREATE and DELETE go on the same page
+            let bytes = prologue.iter().map(String::len).sum();
+
+            if !page.has_room(prologue.len(), bytes) {
+                if page.statements.is_empty() {
+                    return Err(Error::RowTooLarge {
+                        size: bytes as u64,
+                        limit: MAX_PAGE_BYTES as u64,
+                    });
+                }
+
+                return Ok(DumpPage {
+                    statements: page.statements,
+                    next: next(&c),
+                });
+            }
+
+            for s in prologue {
+                page.try_push(s)?;
+            }
+
+            c.created = true;
+        }
+
+        let (n, done) = rows(conn, name, c.offset, &mut page)?;
+        c.offset += n;
+
+        if !done {
+            return Ok(DumpPage {
+                statements: page.statements,
+                next: next(&c),
+            });
+        }
+
+        c.table += 1;
+        c.created = false;
+        c.offset = 0;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT sql FROM sqlite_schema
+         WHERE type IN ('index', 'trigger', 'view') AND sql IS NOT NULL
+         ORDER BY type = 'trigger', rowid
+         LIMIT -1 OFFSET ?1",
+    )?;
+
+    let mut rs = stmt.query([c.offset])?;
+
+    // Triggers come last, as `INSTEAD OF` triggers need their view. Views are
+    // emitted in the order they were created, after those they select from.
+    while let Some(r) = rs.next()? {
+        if !page.try_push(format!("{};", if_not_exists(&r.get::<_, String>(0)?)))? {
+            return Ok(DumpPage {
+                statements: page.statements,
+                next: next(&c),
+            });
+        }
+
+        c.offset += 1;
+    }
+
+    Ok(DumpPage {
+        statements: page.statements,
+        next: None,
+    })
+}
+
+/// Runs a script of `;`-separated statements in a single transaction.
+/// Returns the number of statements executed.
+pub(crate) fn restore(conn: &mut Connection, script: &str) -> Result<u64, Error> {
+    let tx = conn.transaction()?;
+
+    let mut n = 0;
+
+    let mut batch = Batch::new(&tx, script);
+    loop {
+        let res = batch.next().and_then(|stmt| match stmt {
+            Some(mut stmt) => stmt.raw_execute().map(|_| true),
+            None => Ok(false),
+        });
+
+        match res {
+            Ok(true) => n += 1,
+            Ok(false) => break,
+            Err(err) => {
+                return Err(Error::Transaction {
+                    index: n,
+                    error: Box::new(err.into()),
+                })
+            }
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(n)
+}
+
+// Tables to copy, in order. `sqlite_sequence` is created by SQLite itself, so
+// it comes last and is not created by the script.
+fn tables(conn: &Connection) -> Result<Vec<(String, Option<String>)>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT name, CASE WHEN name = 'sqlite_sequence' THEN NULL ELSE sql END
+         FROM sqlite_schema
+         WHERE type = 'table' AND (name NOT LIKE 'sqlite_%' OR name = 'sqlite_sequence')
+         ORDER BY name = 'sqlite_sequence', name",
+    )?;
+
+    let ts = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(ts)
+}
+
+// Appends INSERTs for the rows of `table` starting at `offset` until the page
+// is full, returning how many rows were added and whether they were the last.
+fn rows(
+    conn: &Connection,
+    table: &str,
+    offset: u64,
+    page: &mut Page,
+) -> Result<(u64, bool), Error> {
+    let q = format!("SELECT * FROM {} LIMIT -1 OFFSET ?1", quote_ident(table));
+
+    let mut stmt = conn.prepare(&q)?;
+    let ncols = stmt.column_count();
+
+    let mut rs = stmt.query([offset])?;
+    let mut n = 0;
+
+    while let Some(r) = rs.next()? {
+        let mut s = format!("INSERT INTO {} VALUES(", quote_ident(table));
+
+        for i in 0..ncols {
+            if i > 0 {
+                s.push(',');
+            }
+
+            write_literal(&mut s, r.get_ref(i)?);
+        }
+
+        s.push_str(");");
+
+        if !page.try_push(s)? {
+            return Ok((n, false));
+        }
+
+        n += 1;
+    }
+
+    Ok((n, true))
+}
+
+// SQLite stores schema SQL with the leading keywords in upper case and without
+// `IF NOT EXISTS`, which is added back after the object type.
+fn if_not_exists(sql: &str) -> String {
+    let mut pos = 0;
+
+    for w in sql.split_ascii_whitespace().take(4) {
+        pos = sql[pos..].find(w).map_or(pos, |i| pos + i + w.len());
+
+        if matches!(w, "TABLE" | "INDEX" | "TRIGGER" | "VIEW") {
+            return format!("{} IF NOT EXISTS{}", &sql[..pos], &sql[pos..]);
+        }
+    }
+
+    sql.to_owned()
+}
+
+fn write_literal(s: &mut String, v: ValueRef<'_>) {
+    match v {
+        ValueRef::Null => s.push_str("NULL"),
+        ValueRef::Integer(v) => {
+            let _ = write!(s, "{v}");
+        }
+        ValueRef::Real(v) if v.is_nan() => s.push_str("NULL"),
+        ValueRef::Real(v) if v.is_infinite() => {
+            s.push_str(if v > 0.0 { "9e999" } else { "-9e999" })
+        }
+        ValueRef::Real(v) => {
+            let _ = write!(s, "{v:?}");
+        }
+        ValueRef::Text(v) => match std::str::from_utf8(v) {
+            Ok(v) => {
+                s.push('\'');
+                s.push_str(&v.replace('\'', "''"));
+                s.push('\'');
+            }
+            // Kept byte for byte rather than replacing invalid sequences
+            Err(_) => {
+                s.push_str("CAST(");
+                write_hex(s, v);
+                s.push_str(" AS TEXT)");
+            }
+        },
+        ValueRef::Blob(v) => write_hex(s, v),
+    }
+}
+
+fn write_hex(s: &mut String, v: &[u8]) {
+    s.push_str("X'");
+    hex(s, v);
+    s.push('\'');
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::types::Value;
+
+    use super::*;
+
+    // A database as a canister built from this crate starts out with
+    fn open() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        migrations::apply(&mut conn).unwrap();
+        conn.execute_batch("CREATE TABLE persons (id INTEGER PRIMARY KEY, name TEXT NOT NULL);")
+            .unwrap();
+
+        conn
+    }
+
+    fn dump_all(conn: &Connection) -> Vec<Vec<String>> {
+        let mut pages = vec![];
+        let mut cursor = None;
+
+        loop {
+            let p = dump(conn, cursor).unwrap();
+            pages.push(p.statements);
+
+            match p.next {
+                Some(next) => cursor = Some(next),
+                None => return pages,
+            }
+        }
+    }
+
+    fn contents(conn: &Connection, sql: &str) -> Vec<Vec<Value>> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let n = stmt.column_count();
+
+        stmt.query_map([], |r| (0..n).map(|i| r.get(i)).collect())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trips_into_a_fresh_canister() {
+        let src = open();
+
+        src.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 997)
+             INSERT INTO persons (name) SELECT 'person ' || i FROM n;
+             INSERT INTO persons (name) VALUES ('it''s'), (CAST(X'FF00FE' AS TEXT));
+             CREATE TABLE b (y REAL, z BLOB);
+             INSERT INTO b VALUES (1.5, X'00FF'), (9e999, NULL), (-9e999, zeroblob(3));
+             CREATE TABLE c (k INTEGER PRIMARY KEY AUTOINCREMENT, v);
+             INSERT INTO c (v) VALUES (1), (2);
+             CREATE INDEX b_y ON b (y);
+             CREATE VIEW names AS SELECT name FROM persons;
+             CREATE VIEW a_names AS SELECT * FROM names;
+             CREATE TRIGGER c_log AFTER DELETE ON c BEGIN SELECT 1; END;
+             CREATE TRIGGER a_add INSTEAD OF INSERT ON a_names
+             BEGIN INSERT INTO persons (name) VALUES (NEW.name); END;
+             INSERT INTO _named_queries VALUES ('all', 'SELECT * FROM persons', '', 1);",
+        )
+        .unwrap();
+
+        let pages = dump_all(&src);
+
+        assert!(pages.len() > 1);
+
+        let mut dst = open();
+        dst.execute("INSERT INTO persons (name) VALUES ('seed')", [])
+            .unwrap();
+
+        for p in &pages {
+            restore(&mut dst, &p.join("\n")).unwrap();
+        }
+
+        for sql in [
+            "SELECT id, CAST(name AS BLOB), typeof(name) FROM persons ORDER BY id",
+            "SELECT y, z FROM b ORDER BY rowid",
+            "SELECT * FROM c",
+            "SELECT * FROM _named_queries",
+            "SELECT seq FROM sqlite_sequence",
+            "SELECT type, name FROM sqlite_schema ORDER BY name",
+        ] {
+            assert_eq!(contents(&src, sql), contents(&dst, sql), "{sql}");
+        }
+
+        let raw: Vec<u8> = dst
+            .query_row(
+                "SELECT CAST(name AS BLOB) FROM persons WHERE id = 999",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(raw, [0xFF, 0x00, 0xFE]);
+    }
+
+    #[test]
+    fn emits_each_create_once() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        // The first page ends right where the CREATE of `b` would go
+        conn.execute_batch(
+            "CREATE TABLE a (x);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 996)
+             INSERT INTO a SELECT i FROM n;
+             CREATE TABLE b (y);",
+        )
+        .unwrap();
+
+        let pages = dump_all(&conn);
+        let statements: Vec<_> = pages.iter().flatten().collect();
+
+        assert_eq!(pages.len(), 2);
+        assert!(pages.iter().all(|p| p.len() <= MAX_PAGE_STATEMENTS));
+        assert_eq!(
+            statements
+                .iter()
+                .filter(|s| s.starts_with("CREATE TABLE"))
+                .count(),
+            2
+        );
+
+        let mut dst = Connection::open_in_memory().unwrap();
+        for p in &pages {
+            restore(&mut dst, &p.join("\n")).unwrap();
+        }
+    }
+
+    #[test]
+    fn pages_trailing_objects() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute_batch("CREATE TABLE t (x);").unwrap();
+        for i in 0..MAX_PAGE_STATEMENTS + 10 {
+            conn.execute_batch(&format!("CREATE INDEX i{i} ON t (x);"))
+                .unwrap();
+        }
+
+        let pages = dump_all(&conn);
+
+        assert!(pages.iter().all(|p| p.len() <= MAX_PAGE_STATEMENTS));
+        assert_eq!(
+            pages
+                .iter()
+                .flatten()
+                .filter(|s| s.starts_with("CREATE INDEX IF NOT EXISTS"))
+                .count(),
+            MAX_PAGE_STATEMENTS + 10
+        );
+    }
+
+    #[test]
+    fn keeps_pages_within_reply_limit() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        // Each INSERT takes a little over half a page once hex encoded
+        conn.execute_batch(
+            "CREATE TABLE b (z BLOB);
+             INSERT INTO b VALUES (zeroblob(400000)), (zeroblob(400000)), (zeroblob(400000));",
+        )
+        .unwrap();
+
+        let pages = dump_all(&conn);
+
+        assert!(pages
+            .iter()
+            .all(|p| p.iter().map(String::len).sum::<usize>() <= MAX_PAGE_BYTES));
+        assert_eq!(
+            pages
+                .iter()
+                .flatten()
+                .filter(|s| s.starts_with("INSERT"))
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn rejects_row_larger_than_a_page() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute_batch("CREATE TABLE b (z BLOB); INSERT INTO b VALUES (zeroblob(1200000));")
+            .unwrap();
+
+        let err = dump(&conn, None).unwrap_err();
+
+        assert!(matches!(err, Error::RowTooLarge { .. }));
+    }
+}
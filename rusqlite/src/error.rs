@@ -0,0 +1,118 @@
+use std::fmt;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use rusqlite::ErrorCode;
+
+use crate::{acl::Role, budget, conv, policy};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// A failure reported by SQLite, with its primary and extended result codes.
+    Sqlite {
+        code: i32,
+        extended_code: i32,
+        message: String,
+    },
+
+    /// A failure reported by the stable filesystem, as a WASI errno.
+    Filesystem { errno: u16, message: String },
+
+    /// The request itself was rejected before or while talking to SQLite.
+    Validation(String),
+
+    /// The caller lacks the role required for the method.
+    Unauthorized { required: Role },
+
+    /// The method is reserved to controllers of the canister, whatever the
+    /// caller's role.
+    ControllerRequired,
+
+    /// The SQL was rejected by the statement policy for the caller's role.
+    Denied(String),
+
+    /// The statement was interrupted after using up the per-call instruction budget.
+    InstructionBudgetExceeded { instructions: u64, budget: u64 },
+
+    /// A statement in a batch failed and the batch was rolled back.
+    Transaction { index: u64, error: Box<Error> },
+
+    /// A single row is larger than fits in one reply.
+    RowTooLarge { size: u64, limit: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite {
+                extended_code,
+                message,
+                ..
+            } => write!(f, "sqlite error {extended_code}: {message}"),
+            Error::Filesystem { errno, message } => {
+                write!(f, "filesystem error {errno}: {message}")
+            }
+            Error::Validation(message) => write!(f, "validation error: {message}"),
+            Error::Unauthorized { required } => {
+                write!(f, "unauthorized: requires the {required:?} role")
+            }
+            Error::ControllerRequired => write!(f, "unauthorized: requires a controller"),
+            Error::Denied(reason) => write!(f, "denied: {reason}"),
+            Error::InstructionBudgetExceeded {
+                instructions,
+                budget,
+            } => write!(
+                f,
+                "instruction budget exceeded: used {instructions} of {budget}"
+            ),
+            Error::Transaction { index, error } => {
+                write!(f, "statement {index} failed: {error}")
+            }
+            Error::RowTooLarge { size, limit } => {
+                write!(f, "row of {size} bytes exceeds the reply limit of {limit}")
+            }
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        match err.sqlite_error() {
+            Some(e) if e.code == ErrorCode::AuthorizationForStatementDenied => {
+                match policy::take_denial() {
+                    Some(reason) => Error::Denied(reason),
+                    None => Error::Denied(err.to_string()),
+                }
+            }
+            Some(e) if e.code == ErrorCode::OperationInterrupted => match budget::take_exceeded() {
+                Some((instructions, budget)) => Error::InstructionBudgetExceeded {
+                    instructions,
+                    budget,
+                },
+                None => Error::Sqlite {
+                    code: e.extended_code & 0xff,
+                    extended_code: e.extended_code,
+                    message: err.to_string(),
+                },
+            },
+            Some(e) => Error::Sqlite {
+                code: e.extended_code & 0xff,
+                extended_code: e.extended_code,
+                message: err.to_string(),
+            },
+            None => Error::Validation(err.to_string()),
+        }
+    }
+}
+
+impl From<stable_fs::error::Error> for Error {
+    fn from(err: stable_fs::error::Error) -> Self {
+        let message = err.to_string();
+
+        Error::Filesystem {
+            errno: conv::error(err).raw(),
+            message,
+        }
+    }
+}
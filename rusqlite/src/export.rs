@@ -0,0 +1,123 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    rw::{read_range, remove, write_range},
+    snapshot::Snapshot,
+    FILESYSTEM, MAX_PAGE_BYTES,
+};
+
+pub(crate) const EXPORT_PATH: &str = "export.sqlite3";
+const DB_PATH: &str = "db.sqlite3";
+
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Chunks copied per call, keeping each call well within the instruction limit
+const STEP_CHUNKS: u64 = 16;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExportInfo {
+    pub size: u64,
+    pub sha256: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ExportStatus {
+    /// The snapshot is still being copied, call `export_continue`.
+    Copying { copied: u64, size: u64 },
+
+    /// The snapshot is complete and can be read with `export_chunk`.
+    Ready(ExportInfo),
+}
+
+enum State {
+    Copying(Snapshot),
+    Ready(ExportInfo),
+}
+
+thread_local! {
+    // Kept on the heap, so an upgrade cancels an export in progress
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+/// Starts copying the database into the staging file. The copy proceeds a
+/// few chunks per call and starts over if the database is written to in
+/// between, so the finished export is always one consistent snapshot.
+pub(crate) fn begin() -> Result<ExportStatus, Error> {
+    FILESYSTEM.with(|fs| remove(fs.borrow_mut(), EXPORT_PATH))?;
+
+    let s = Snapshot::start(DB_PATH)?;
+    STATE.with(|st| st.replace(Some(State::Copying(s))));
+
+    step()
+}
+
+pub(crate) fn step() -> Result<ExportStatus, Error> {
+    let st = STATE.with(|st| st.take());
+
+    let mut s = match st {
+        Some(State::Copying(s)) => s,
+        Some(State::Ready(info)) => {
+            STATE.with(|st| st.replace(Some(State::Ready(info.clone()))));
+            return Ok(ExportStatus::Ready(info));
+        }
+        None => return Err(Error::Validation("no export in progress".into())),
+    };
+
+    let res = s.step(DB_PATH, COPY_CHUNK_SIZE, STEP_CHUNKS, |offset, bs| {
+        // A restarted copy may be shorter than the one it replaces
+        if offset == 0 {
+            FILESYSTEM.with(|fs| remove(fs.borrow_mut(), EXPORT_PATH))?;
+        }
+
+        FILESYSTEM.with(|fs| write_range(fs.borrow_mut(), EXPORT_PATH, offset, bs))
+    });
+
+    if let Err(err) = res {
+        FILESYSTEM.with(|fs| remove(fs.borrow_mut(), EXPORT_PATH))?;
+        return Err(err);
+    }
+
+    let (st, status) = if s.done() {
+        let info = ExportInfo {
+            size: s.size,
+            sha256: s.sha256(),
+        };
+
+        (State::Ready(info.clone()), ExportStatus::Ready(info))
+    } else {
+        let status = ExportStatus::Copying {
+            copied: s.copied,
+            size: s.size,
+        };
+
+        (State::Copying(s), status)
+    };
+
+    STATE.with(|c| c.replace(Some(st)));
+
+    Ok(status)
+}
+
+pub(crate) fn chunk(offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+    if len > MAX_PAGE_BYTES as u64 {
+        return Err(Error::Validation(format!(
+            "chunk length {len} exceeds the maximum of {MAX_PAGE_BYTES}"
+        )));
+    }
+
+    if !STATE.with(|st| matches!(*st.borrow(), Some(State::Ready(_)))) {
+        return Err(Error::Validation("export is not ready".into()));
+    }
+
+    FILESYSTEM.with(|fs| read_range(fs.borrow_mut(), EXPORT_PATH, offset, len as usize))
+}
+
+pub(crate) fn end() -> Result<(), Error> {
+    STATE.with(|st| st.take());
+
+    FILESYSTEM.with(|fs| remove(fs.borrow_mut(), EXPORT_PATH))
+}
@@ -0,0 +1,42 @@
+use rusqlite::{functions::FunctionFlags, types::ValueRef, Connection, Error};
+
+// SQLite's default SQLITE_MAX_LENGTH, the largest blob `randomblob` returns
+const MAX_BLOB_LENGTH: i64 = 1_000_000_000;
+
+/// Replaces SQLite's `random()` and `randomblob(N)` with versions drawing from
+/// `rng`. SQLite seeds its own generator from the clock under WASI and keeps
+/// it on the heap, where a query call could read ahead of the next update.
+pub(crate) fn register(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function("random", 0, FunctionFlags::SQLITE_UTF8, |_| {
+        let mut bs = [0; 8];
+        fill(&mut bs)?;
+
+        Ok(i64::from_le_bytes(bs))
+    })?;
+
+    conn.create_scalar_function("randomblob", 1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        // Same conversion as `sqlite3_value_int64`, and at least one byte
+        let n = match ctx.get_raw(0) {
+            ValueRef::Integer(v) => v,
+            ValueRef::Real(v) => v as i64,
+            ValueRef::Text(v) => std::str::from_utf8(v)
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(0),
+            ValueRef::Null | ValueRef::Blob(_) => 0,
+        };
+
+        if n > MAX_BLOB_LENGTH {
+            return Err(Error::UserFunctionError("string or blob too big".into()));
+        }
+
+        let mut bs = vec![0; n.max(1) as usize];
+        fill(&mut bs)?;
+
+        Ok(bs)
+    })
+}
+
+fn fill(buf: &mut [u8]) -> rusqlite::Result<()> {
+    rng::fill(buf).map_err(|err| Error::UserFunctionError(err.into()))
+}
@@ -0,0 +1,429 @@
+use std::fmt::Write;
+
+use candid::CandidType;
+use rusqlite::Connection;
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+
+use crate::{
+    conv::hex,
+    error::Error,
+    named::{self, NamedQuery},
+    types::{Page, QueryResult, SqlRow, SqlValue},
+    MAX_PAGE_BYTES,
+};
+
+/// Request as passed in by the HTTP gateway.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+enum Format {
+    Json,
+    Csv,
+}
+
+/// Serves `GET /q/<name>?<param>=<value>...` by running the named query with
+/// the Reader role, converting each argument to its declared type. Callers
+/// are not authenticated, so only queries registered as `public` are served.
+/// The result is a JSON array of objects, or CSV with a header row if the
+/// `Accept` header prefers `text/csv`. At most one page of rows is returned;
+/// the `x-truncated` header is set if there were more.
+///
+/// Responses are not certified, so they have to be fetched through the raw
+/// gateway domain.
+pub(crate) fn handle(conn: &Connection, req: HttpRequest) -> HttpResponse {
+    if req.method != "GET" {
+        return text(405, "method not allowed");
+    }
+
+    let (path, qs) = req.url.split_once('?').unwrap_or((&req.url, ""));
+
+    let Some(name) = path.strip_prefix("/q/").filter(|v| !v.contains('/')) else {
+        return text(404, "not found");
+    };
+
+    // Private queries are indistinguishable from missing ones
+    let q = match named::lookup(conn, &decode(name)) {
+        Ok(Some(v)) if v.public => v,
+        Ok(_) => return text(404, "no such query"),
+        Err(err) => return error(err),
+    };
+
+    let args: Vec<(String, String)> = qs
+        .split('&')
+        .filter(|v| !v.is_empty())
+        .map(|kv| {
+            let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+            (decode(k), decode(v))
+        })
+        .collect();
+
+    let format = accept(&req.headers);
+
+    let res = match run(conn, &q, &args) {
+        Ok(v) => v,
+        Err(err) => return error(err),
+    };
+
+    let columns: Vec<_> = res.columns.into_iter().map(|c| c.name).collect();
+
+    // The page is sized for Candid, which encodes blobs and column names
+    // more compactly, so the body may hold fewer of its rows
+    let (ctype, body) = match format {
+        Format::Json => ("application/json", json(&columns, &res.rows)),
+        Format::Csv => ("text/csv; charset=utf-8", csv(&columns, &res.rows)),
+    };
+
+    let (body, truncated) = match body {
+        Ok(v) => v,
+        Err(err) => return error(err),
+    };
+
+    let mut headers = vec![("content-type".to_owned(), ctype.to_owned())];
+    if truncated || res.next.is_some() {
+        headers.push(("x-truncated".to_owned(), "true".to_owned()));
+    }
+
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body,
+    }
+}
+
+// Binds each declared parameter from the query string argument of the same name
+fn run(conn: &Connection, q: &NamedQuery, args: &[(String, String)]) -> Result<QueryResult, Error> {
+    let args = q
+        .params
+        .iter()
+        .map(|p| match args.iter().find(|(k, _)| *k == p.name) {
+            Some((_, v)) => named::parse_arg(p, v),
+            None => Err(Error::Validation(format!("missing parameter {:?}", p.name))),
+        })
+        .collect::<Result<_, _>>()?;
+
+    named::run(conn, q, args, &Page::default())
+}
+
+// Picks whichever supported type the client lists first, defaulting to JSON
+fn accept(headers: &[(String, String)]) -> Format {
+    let accept = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("accept"))
+        .map_or("", |(_, v)| v.as_str());
+
+    for t in accept.split(',') {
+        match t.split(';').next().unwrap_or_default().trim() {
+            "text/csv" => return Format::Csv,
+            "application/json" => return Format::Json,
+            _ => {}
+        }
+    }
+
+    Format::Json
+}
+
+fn error(err: Error) -> HttpResponse {
+    let status = match err {
+        Error::Validation(_) => 400,
+        Error::Unauthorized { .. } | Error::ControllerRequired | Error::Denied(_) => 403,
+        Error::InstructionBudgetExceeded { .. } => 503,
+        _ => 500,
+    };
+
+    text(status, &err.to_string())
+}
+
+fn text(status_code: u16, msg: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![(
+            "content-type".to_owned(),
+            "text/plain; charset=utf-8".to_owned(),
+        )],
+        body: format!("{msg}\n").into_bytes(),
+    }
+}
+
+// Percent-decodes a URL component, treating '+' as a space
+fn decode(v: &str) -> String {
+    let bs = v.as_bytes();
+    let mut out = Vec::with_capacity(bs.len());
+
+    let mut i = 0;
+    while i < bs.len() {
+        let escaped = (bs[i] == b'%')
+            .then(|| v.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+
+        match (bs[i], escaped) {
+            (_, Some(b)) => {
+                out.push(b);
+                i += 2;
+            }
+            (b'+', _) => out.push(b' '),
+            (b, _) => out.push(b),
+        }
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// One object per row, keyed by column name in column order
+struct JsonRow<'a> {
+    columns: &'a [String],
+    row: &'a SqlRow,
+}
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut m = s.serialize_map(Some(self.columns.len()))?;
+
+        for (c, v) in self.columns.iter().zip(self.row) {
+            // JSON has no representation for NaN or infinities, which
+            // become null
+            let v = match v {
+                SqlValue::Null => serde_json::Value::Null,
+                SqlValue::Integer(v) => (*v).into(),
+                SqlValue::Real(v) => (*v).into(),
+                SqlValue::Text(v) => v.as_str().into(),
+                SqlValue::Blob(v) => {
+                    let mut s = String::new();
+                    hex(&mut s, v);
+                    s.into()
+                }
+            };
+
+            m.serialize_entry(c, &v)?;
+        }
+
+        m.end()
+    }
+}
+
+// Encodes `rows` as a JSON array, returning the body and whether rows were
+// left out to keep it within the reply limit
+fn json(columns: &[String], rows: &[SqlRow]) -> Result<(Vec<u8>, bool), Error> {
+    let rows = rows
+        .iter()
+        .map(|row| serde_json::to_vec(&JsonRow { columns, row }).expect("failed to encode row"));
+
+    fit(b"[".to_vec(), rows, b",", b"]")
+}
+
+// NULL is an empty field, matching what the bulk CSV import reads back as NULL
+fn csv(columns: &[String], rows: &[SqlRow]) -> Result<(Vec<u8>, bool), Error> {
+    let header: Vec<_> = columns.iter().map(|c| SqlValue::Text(c.clone())).collect();
+
+    let rows = rows.iter().map(|row| csv_row(row).into_bytes());
+
+    fit(csv_row(&header).into_bytes(), rows, b"", b"")
+}
+
+// Joins encoded rows between `head` and `tail` for as long as the body stays
+// within the reply limit, returning it and whether rows were left out
+fn fit(
+    head: Vec<u8>,
+    rows: impl Iterator<Item = Vec<u8>>,
+    sep: &[u8],
+    tail: &[u8],
+) -> Result<(Vec<u8>, bool), Error> {
+    let mut out = head;
+    let mut truncated = false;
+
+    for (i, bs) in rows.enumerate() {
+        let sep = if i > 0 { sep } else { b"" };
+
+        if out.len() + sep.len() + bs.len() + tail.len() > MAX_PAGE_BYTES {
+            if i == 0 {
+                return Err(Error::RowTooLarge {
+                    size: bs.len() as u64,
+                    limit: MAX_PAGE_BYTES as u64,
+                });
+            }
+
+            truncated = true;
+            break;
+        }
+
+        out.extend_from_slice(sep);
+        out.extend_from_slice(&bs);
+    }
+
+    out.extend_from_slice(tail);
+
+    Ok((out, truncated))
+}
+
+fn csv_row(row: &SqlRow) -> String {
+    let mut s = String::new();
+
+    for (j, v) in row.iter().enumerate() {
+        if j > 0 {
+            s.push(',');
+        }
+
+        match v {
+            SqlValue::Null => {}
+            SqlValue::Integer(v) => {
+                let _ = write!(s, "{v}");
+            }
+            SqlValue::Real(v) => {
+                let _ = write!(s, "{v:?}");
+            }
+            SqlValue::Text(v) => csv_field(&mut s, v),
+            SqlValue::Blob(v) => hex(&mut s, v),
+        }
+    }
+
+    s.push_str("\r\n");
+
+    s
+}
+
+fn csv_field(s: &mut String, v: &str) {
+    // Quoting empty strings keeps them apart from NULL
+    if !v.is_empty() && !v.contains([',', '"', '\r', '\n']) {
+        s.push_str(v);
+        return;
+    }
+
+    s.push('"');
+    s.push_str(&v.replace('"', "\"\""));
+    s.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(accept: &str) -> Vec<(String, String)> {
+        vec![("Accept".to_owned(), accept.to_owned())]
+    }
+
+    #[test]
+    fn decodes_url_components() {
+        assert_eq!(decode("a%20b+c"), "a b c");
+        assert_eq!(decode("%C3%A9%2b%2F"), "\u{e9}+/");
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(decode("%FF"), "\u{fffd}");
+    }
+
+    #[test]
+    fn picks_format_from_accept() {
+        let csv = |h: &[(String, String)]| matches!(accept(h), Format::Csv);
+
+        assert!(!csv(&[]));
+        assert!(!csv(&headers("*/*")));
+        assert!(csv(&headers("text/csv")));
+        assert!(csv(&headers("text/html, text/csv;q=0.9, application/json")));
+        assert!(!csv(&headers("application/json; charset=utf-8, text/csv")));
+        assert!(csv(&[(
+            "accept".to_owned(),
+            " text/csv ; header=present".to_owned()
+        )]));
+    }
+
+    #[test]
+    fn encodes_rows_as_json() {
+        let columns = ["b".to_owned(), "a".to_owned(), "c".to_owned()];
+        let rows = vec![
+            vec![
+                SqlValue::Text("q\"\n\u{1}".into()),
+                SqlValue::Real(f64::NAN),
+                SqlValue::Blob(vec![0, 255]),
+            ],
+            vec![SqlValue::Null, SqlValue::Integer(-1), SqlValue::Real(0.5)],
+        ];
+
+        let (body, truncated) = json(&columns, &rows).unwrap();
+        let body = String::from_utf8(body).unwrap();
+
+        assert!(!truncated);
+        assert_eq!(
+            body,
+            r#"[{"b":"q\"\n\u0001","a":null,"c":"00ff"},{"b":null,"a":-1,"c":0.5}]"#
+        );
+    }
+
+    #[test]
+    fn truncates_body_to_reply_limit() {
+        let columns = ["v".to_owned()];
+
+        // Each blob fits a Candid page three times over, but not in hex
+        let rows = vec![vec![SqlValue::Blob(vec![0; MAX_PAGE_BYTES / 3])]; 3];
+
+        let (body, truncated) = json(&columns, &rows).unwrap();
+        assert!(truncated);
+        assert!(body.len() <= MAX_PAGE_BYTES);
+        assert_eq!(
+            serde_json::from_slice::<Vec<serde_json::Value>>(&body)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let (body, truncated) = csv(&columns, &rows).unwrap();
+        assert!(truncated);
+        assert_eq!(body.split(|&b| b == b'\n').count(), 3);
+
+        let rows = vec![vec![SqlValue::Blob(vec![0; MAX_PAGE_BYTES / 2 + 1])]];
+        assert!(matches!(
+            json(&columns, &rows),
+            Err(Error::RowTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn serves_only_public_queries() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migrations::apply(&mut conn).unwrap();
+
+        for (name, public) in [("open", true), ("closed", false)] {
+            let q = NamedQuery {
+                name: name.into(),
+                sql: "SELECT 1 AS one".into(),
+                params: vec![],
+                public,
+            };
+
+            named::register(&conn, &q).unwrap();
+        }
+
+        let get = |url: &str| {
+            handle(
+                &conn,
+                HttpRequest {
+                    method: "GET".into(),
+                    url: url.into(),
+                    headers: vec![],
+                    body: vec![],
+                },
+            )
+        };
+
+        let res = get("/q/open");
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.body, br#"[{"one":1}]"#);
+
+        assert_eq!(get("/q/closed").status_code, 404);
+        assert_eq!(get("/q/missing").status_code, 404);
+    }
+}
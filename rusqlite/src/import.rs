@@ -0,0 +1,259 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use crate::{
+    budget,
+    checksum::Checksum,
+    error::Error,
+    migrations, open_connection,
+    rw::{read_range, remove, rename, write_chunks, write_range},
+    trap,
+    types::SchemaVersion,
+    CONN, FILESYSTEM, SETTINGS,
+};
+
+const IMPORT_PATH: &str = "import.sqlite3";
+const DB_PATH: &str = "db.sqlite3";
+const JOURNAL_PATH: &str = "db.sqlite3-journal";
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+// Largest database accepted when no smaller `max_db_size` is configured. The
+// staging file sits next to the live database until the import commits, and
+// every table must be checked within a single message.
+#[cfg(feature = "stable-storage")]
+const MAX_IMPORT_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+// Both copies live on the 4 GiB heap, next to SQLite's page cache
+#[cfg(not(feature = "stable-storage"))]
+const MAX_IMPORT_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Instructions after which `commit` stops checking tables and asks to be
+// called again, leaving the rest of the budget to the table in progress
+const CHECK_STEP_INSTRUCTIONS: u64 = 5_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ImportStatus {
+    /// Tables are still being checked, call `import_commit` again.
+    Checking { checked: u64, total: u64 },
+
+    /// The import replaced the live database.
+    Committed(SchemaVersion),
+}
+
+struct Pending {
+    size: u64,
+    sha256: Vec<u8>,
+
+    // Bytes received so far, hashed in order
+    received: u64,
+    checksum: Checksum,
+
+    // Tables still to be checked, once every byte was received
+    unchecked: Option<Vec<String>>,
+    tables: u64,
+}
+
+thread_local! {
+    // Kept on the heap, so an upgrade cancels an import in progress
+    static PENDING: RefCell<Option<Pending>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn begin(size: u64, sha256: Vec<u8>) -> Result<(), Error> {
+    if sha256.len() != 32 {
+        return Err(Error::Validation("sha256 must be 32 bytes".into()));
+    }
+
+    let limit = SETTINGS
+        .with(|m| m.borrow().get(&()))
+        .and_then(|s| s.max_db_size)
+        .map_or(MAX_IMPORT_SIZE, |v| v.min(MAX_IMPORT_SIZE));
+
+    if size > limit {
+        return Err(Error::Validation(format!(
+            "size {size} exceeds the maximum of {limit}"
+        )));
+    }
+
+    // Start from an empty staging file
+    FILESYSTEM.with(|fs| remove(fs.borrow_mut(), IMPORT_PATH))?;
+    FILESYSTEM.with(|fs| write_chunks(fs.borrow_mut(), IMPORT_PATH, []))?;
+
+    PENDING.with(|p| {
+        p.replace(Some(Pending {
+            size,
+            sha256,
+            received: 0,
+            checksum: Checksum::default(),
+            unchecked: None,
+            tables: 0,
+        }))
+    });
+
+    Ok(())
+}
+
+/// Appends `bs` to the staging file. Chunks must arrive in order, so the file
+/// can be hashed as it is received.
+pub(crate) fn chunk(offset: u64, bs: &[u8]) -> Result<(), Error> {
+    PENDING.with(|p| {
+        let mut p = p.borrow_mut();
+
+        let Some(p) = p.as_mut() else {
+            return Err(Error::Validation("no import in progress".into()));
+        };
+
+        if p.unchecked.is_some() {
+            return Err(Error::Validation("import is being committed".into()));
+        }
+
+        if offset != p.received {
+            return Err(Error::Validation(format!(
+                "chunk at offset {offset}, expected offset {}",
+                p.received
+            )));
+        }
+
+        let end = offset.checked_add(bs.len() as u64);
+
+        if end.is_none_or(|end| end > p.size) {
+            return Err(Error::Validation(format!(
+                "chunk at offset {offset} runs past the declared size {}",
+                p.size
+            )));
+        }
+
+        FILESYSTEM.with(|fs| write_range(fs.borrow_mut(), IMPORT_PATH, offset, bs))?;
+
+        p.checksum.update(bs);
+        p.received += bs.len() as u64;
+
+        Ok(())
+    })
+}
+
+/// Validates the staging file and swaps it in as the live database. Tables
+/// are checked a few per call, so this returns `Checking` until all of them
+/// passed, and `None` once the import replaced the live database.
+pub(crate) fn commit() -> Result<Option<ImportStatus>, Error> {
+    let status = PENDING.with(|p| {
+        let mut p = p.borrow_mut();
+
+        let Some(p) = p.as_mut() else {
+            return Err(Error::Validation("no import in progress".into()));
+        };
+
+        if p.unchecked.is_none() {
+            verify(p)?;
+        }
+
+        check_tables(p)
+    })?;
+
+    if status.is_some() {
+        return Ok(status);
+    }
+
+    // Past this point failures trap, rolling back the whole message so the
+    // live database is never left half-replaced
+    if let Err(err) = swap() {
+        trap(&format!("failed to swap in imported database: {err}"));
+    }
+
+    PENDING.with(|p| p.replace(None));
+
+    Ok(None)
+}
+
+// Checks the staging file as a whole and lists the tables to check
+fn verify(p: &mut Pending) -> Result<(), Error> {
+    if p.received != p.size {
+        return Err(Error::Validation(format!(
+            "imported {} bytes, expected {}",
+            p.received, p.size
+        )));
+    }
+
+    if p.checksum.finalize() != p.sha256 {
+        return Err(Error::Validation("import checksum mismatch".into()));
+    }
+
+    let magic =
+        FILESYSTEM.with(|fs| read_range(fs.borrow_mut(), IMPORT_PATH, 0, SQLITE_MAGIC.len()))?;
+
+    if magic != SQLITE_MAGIC {
+        return Err(Error::Validation("not an SQLite database".into()));
+    }
+
+    let conn = Connection::open(IMPORT_PATH)?;
+
+    let version = migrations::current_version(&conn)?;
+    if version > migrations::target_version() {
+        return Err(Error::Validation(format!(
+            "database is at schema version {version}, newer than this canister"
+        )));
+    }
+
+    let mut stmt = conn.prepare("SELECT name FROM sqlite_schema WHERE type = 'table'")?;
+    let tables: Vec<String> = stmt
+        .query_map([], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    p.tables = tables.len() as u64;
+    p.unchecked = Some(tables);
+
+    Ok(())
+}
+
+// Runs the integrity check of one table after another until the step's
+// instructions are used up. A table too large to check in one message fails
+// with the instruction budget error rather than trapping.
+fn check_tables(p: &mut Pending) -> Result<Option<ImportStatus>, Error> {
+    let conn = Connection::open(IMPORT_PATH)?;
+    conn.progress_handler(budget::CHECK_INTERVAL, Some(budget::progress_handler));
+
+    let unchecked = p.unchecked.as_mut().expect("tables not listed");
+
+    while let Some(table) = unchecked.last() {
+        if ic_cdk::api::performance_counter(0) > CHECK_STEP_INSTRUCTIONS {
+            return Ok(Some(ImportStatus::Checking {
+                checked: p.tables - unchecked.len() as u64,
+                total: p.tables,
+            }));
+        }
+
+        let mut stmt = conn.prepare("SELECT * FROM pragma_integrity_check(?1)")?;
+        let problems: Vec<String> = stmt
+            .query_map([table], |r| r.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        if problems != ["ok"] {
+            return Err(Error::Validation(format!(
+                "integrity check of {table} failed: {}",
+                problems.join("; ")
+            )));
+        }
+
+        unchecked.pop();
+    }
+
+    Ok(None)
+}
+
+fn swap() -> Result<(), Error> {
+    // Release the live database before replacing it
+    let conn = Connection::open_in_memory()?;
+    CONN.with(|c| c.replace(conn));
+
+    FILESYSTEM.with(|fs| remove(fs.borrow_mut(), JOURNAL_PATH))?;
+    FILESYSTEM.with(|fs| remove(fs.borrow_mut(), DB_PATH))?;
+    FILESYSTEM.with(|fs| rename(fs.borrow_mut(), IMPORT_PATH, DB_PATH))?;
+
+    let conn = open_connection()?;
+    CONN.with(|c| c.replace(conn));
+
+    Ok(())
+}
@@ -1,21 +1,89 @@
 use std::cell::RefCell;
 
+use acl::Role;
+use backup::Header;
+use bulk::{BulkResult, Format};
+use candid::Principal;
+use config::{CanisterArgs, Environment, InitArgs, Settings, UpgradeArgs};
+use dump::DumpPage;
+use error::Error;
+use export::ExportStatus;
+use http::{HttpRequest, HttpResponse};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     DefaultMemoryImpl, StableBTreeMap,
 };
-use rusqlite::{Connection, Row};
-use rw::{read_into_vec, write_vec};
-use stable_fs::{fs::FileSystem, storage::transient::TransientStorage};
+use import::ImportStatus;
+use maintenance::{Job, JobStatus, Task};
+use named::NamedQuery;
+use rusqlite::{params_from_iter, types::Value, Connection, Row};
+use stable_fs::fs::FileSystem;
+use stats::StatementStats;
+
+#[cfg(feature = "stable-storage")]
+use stable_fs::storage::stable::StableStorage;
+#[cfg(not(feature = "stable-storage"))]
+use stable_fs::storage::transient::TransientStorage;
 
 mod wasi;
 use wasi::inject_shims;
 
+mod acl;
+mod backup;
+mod budget;
+mod bulk;
+mod checksum;
+mod config;
 mod conv;
+mod dump;
+mod error;
+mod export;
+mod functions;
+mod http;
+mod import;
+mod maintenance;
+mod migrations;
+mod named;
+mod page;
+mod policy;
 mod polyfill;
 mod rw;
+mod snapshot;
+mod stats;
+
+mod types;
+use types::{ExecuteResult, Page, QueryResult, SchemaVersion, SqlValue, Statement};
+
+const BACKUP_MEMORY_ID: MemoryId = MemoryId::new(0);
+const BACKUP_HEADER_MEMORY_ID: MemoryId = MemoryId::new(2);
+const BACKUP_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ROLES_MEMORY_ID: MemoryId = MemoryId::new(5);
+const MAINTENANCE_MEMORY_ID: MemoryId = MemoryId::new(6);
+const ENVIRONMENT_MEMORY_ID: MemoryId = MemoryId::new(7);
+
+// Handed to stable-fs, which needs ten memories of its own
+#[cfg(feature = "stable-storage")]
+const FILESYSTEM_MEMORY_IDS: std::ops::Range<u8> = 10..20;
+
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+// Largest reply payload assembled by any method, leaving headroom under the
+// 2 MiB reply limit for Candid framing
+const MAX_PAGE_BYTES: usize = 1536 * 1024;
 
 thread_local! {
+    #[cfg(feature = "stable-storage")]
+    pub static FILESYSTEM: RefCell<FileSystem> = RefCell::new({
+        let s = MEMORY_MANAGER.with(|m| {
+            StableStorage::new_with_memory_manager(&m.borrow(), FILESYSTEM_MEMORY_IDS)
+        });
+        let s = Box::new(s);
+
+        FileSystem::new(s).expect("failed to init filesystem")
+    });
+
+    #[cfg(not(feature = "stable-storage"))]
     pub static FILESYSTEM: RefCell<FileSystem> = RefCell::new({
         let s = TransientStorage::new();
         let s = Box::new(s);
@@ -32,108 +100,618 @@ thread_local! {
         RefCell::new(mm)
     };
 
+    // Legacy single-value backup, only read to migrate older canisters
     static BACKUP: RefCell<StableBTreeMap<(), Vec<u8>, VirtualMemory<DefaultMemoryImpl>>> = {
-        let m = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)));
+        let m = MEMORY_MANAGER.with(|m| m.borrow().get(BACKUP_MEMORY_ID));
         let v = StableBTreeMap::init(m);
 
         RefCell::new(v)
-    }
+    };
+
+    static BACKUP_HEADER: RefCell<StableBTreeMap<(), Header, VirtualMemory<DefaultMemoryImpl>>> = {
+        let m = MEMORY_MANAGER.with(|m| m.borrow().get(BACKUP_HEADER_MEMORY_ID));
+        let v = StableBTreeMap::init(m);
+
+        RefCell::new(v)
+    };
+
+    static BACKUP_CHUNKS: RefCell<StableBTreeMap<u64, Vec<u8>, VirtualMemory<DefaultMemoryImpl>>> = {
+        let m = MEMORY_MANAGER.with(|m| m.borrow().get(BACKUP_CHUNKS_MEMORY_ID));
+        let v = StableBTreeMap::init(m);
+
+        RefCell::new(v)
+    };
+
+    static SETTINGS: RefCell<StableBTreeMap<(), Settings, VirtualMemory<DefaultMemoryImpl>>> = {
+        let m = MEMORY_MANAGER.with(|m| m.borrow().get(SETTINGS_MEMORY_ID));
+        let v = StableBTreeMap::init(m);
+
+        RefCell::new(v)
+    };
+
+    static ROLES: RefCell<StableBTreeMap<Principal, Role, VirtualMemory<DefaultMemoryImpl>>> = {
+        let m = MEMORY_MANAGER.with(|m| m.borrow().get(ROLES_MEMORY_ID));
+        let v = StableBTreeMap::init(m);
+
+        RefCell::new(v)
+    };
+
+    static MAINTENANCE: RefCell<StableBTreeMap<Task, Job, VirtualMemory<DefaultMemoryImpl>>> = {
+        let m = MEMORY_MANAGER.with(|m| m.borrow().get(MAINTENANCE_MEMORY_ID));
+        let v = StableBTreeMap::init(m);
+
+        RefCell::new(v)
+    };
+
+    static ENVIRONMENT: RefCell<StableBTreeMap<(), Environment, VirtualMemory<DefaultMemoryImpl>>> = {
+        let m = MEMORY_MANAGER.with(|m| m.borrow().get(ENVIRONMENT_MEMORY_ID));
+        let v = StableBTreeMap::init(m);
+
+        RefCell::new(v)
+    };
 }
 
 thread_local! {
     pub static CONN: RefCell<Connection> = RefCell::new({
-        Connection::open("db.sqlite3").expect("failed to open connection")
+        open_connection().expect("failed to open connection")
     });
 }
 
+fn open_connection() -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open("db.sqlite3")?;
+    conn.authorizer(Some(policy::authorizer));
+    conn.progress_handler(budget::CHECK_INTERVAL, Some(budget::progress_handler));
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+    conn.commit_hook(Some(snapshot::commit_hook));
+    functions::register(&conn)?;
+
+    Ok(conn)
+}
+
+// The whole database is backed up within this one message, so a transient
+// build can only be upgraded while its database can be read through within
+// the upgrade's instruction limit. Builds with `stable-storage` keep the
+// database in stable memory and need no backup.
+#[cfg(not(feature = "stable-storage"))]
 #[ic_cdk::pre_upgrade]
 fn pre_upgrade_fn() {
-    let bs = FILESYSTEM.with(|fs| {
-        read_into_vec(
-            fs.borrow_mut(), // fs
-            "db.sqlite3",    // path
-        )
-    });
+    let _logs = polyfill::LogScope;
 
-    BACKUP.with(|m| {
-        m.borrow_mut().insert(
-            (), // k
-            bs, // v
-        )
-    });
+    if let Err(err) = backup::save("db.sqlite3") {
+        trap(&format!("failed to back up database: {err}"));
+    }
+}
+
+#[cfg(not(feature = "stable-storage"))]
+#[ic_cdk::post_upgrade]
+fn post_upgrade_fn(args: Option<CanisterArgs>) {
+    let _logs = polyfill::LogScope;
+
+    inject_shims();
+    set_environment(upgrade_args(args).environment);
+
+    match backup::restore("db.sqlite3") {
+        Ok(true) => backup::clear(),
+        Ok(false) => trap("no database backup"),
+        Err(err) => trap(&format!("failed to restore database: {err}")),
+    }
+
+    reopen();
+    maintenance::arm();
+    rng::schedule();
 }
 
+#[cfg(feature = "stable-storage")]
 #[ic_cdk::post_upgrade]
-fn post_upgrade_fn() {
+fn post_upgrade_fn(args: Option<CanisterArgs>) {
+    let _logs = polyfill::LogScope;
+
     inject_shims();
+    set_environment(upgrade_args(args).environment);
 
-    let mut bs = BACKUP
-        .with(|m| m.borrow().get(&()))
-        .expect("no database backup");
+    // Migrate a backup left behind by a transient build. Periodic backups
+    // sit next to a live database and must not replace it.
+    let size = FILESYSTEM.with(|fs| rw::size(fs.borrow_mut(), "db.sqlite3"));
 
-    FILESYSTEM.with(|fs| {
-        write_vec(
-            fs.borrow_mut(), // fs
-            "db.sqlite3",    // path
-            &mut bs,         // bs
-        );
-    });
+    match size.and_then(|v| {
+        if v == 0 {
+            backup::restore("db.sqlite3")
+        } else {
+            Ok(false)
+        }
+    }) {
+        Ok(true) => backup::clear(),
+        Ok(false) => {}
+        Err(err) => trap(&format!("failed to restore database: {err}")),
+    }
+
+    reopen();
+    maintenance::arm();
+    rng::schedule();
+}
+
+fn upgrade_args(args: Option<CanisterArgs>) -> UpgradeArgs {
+    match args {
+        Some(CanisterArgs::Upgrade(v)) => v,
+        Some(CanisterArgs::Init(_)) => trap("expected Upgrade arguments on upgrade"),
+        None => UpgradeArgs::default(),
+    }
+}
+
+// Flushes the log buffers first, which a trap would discard
+pub(crate) fn trap(msg: &str) -> ! {
+    polyfill::flush_logs();
+    ic_cdk::trap(msg)
+}
+
+// Keeps the stored environment if none is given
+fn set_environment(env: Option<Environment>) {
+    if let Some(env) = env {
+        if let Err(err) = env.store() {
+            trap(&format!("invalid environment: {err}"));
+        }
+    }
+}
+
+fn reopen() {
+    if let Err(err) = configure() {
+        trap(&format!("failed to open database: {err}"));
+    }
+}
+
+// Re-applies the persisted settings and brings the schema up to date
+fn configure() -> Result<(), Error> {
+    CONN.with(|conn| {
+        let mut conn = conn.borrow_mut();
+
+        let settings = SETTINGS.with(|m| m.borrow().get(&())).unwrap_or_default();
+        settings.apply(&conn)?;
+
+        migrations::apply(&mut conn)?;
+
+        Ok(())
+    })
 }
 
 #[ic_cdk::init]
-fn init_fn() {
+fn init_fn(args: Option<CanisterArgs>) {
+    let _logs = polyfill::LogScope;
+
     inject_shims();
+    rng::schedule();
+
+    let mut args = match args {
+        Some(CanisterArgs::Init(v)) => v,
+        Some(CanisterArgs::Upgrade(_)) => trap("expected Init arguments on install"),
+        None => InitArgs::default(),
+    };
+    set_environment(args.environment.take());
+
+    if let Err(err) = init(args) {
+        trap(&format!("failed to initialize database: {err}"));
+    }
+}
+
+fn init(args: InitArgs) -> Result<(), Error> {
+    let settings = args.settings.unwrap_or_default();
+    settings.validate()?;
 
     CONN.with(|conn| {
-        let conn = conn.borrow_mut();
+        let mut conn = conn.borrow_mut();
 
-        let q = "
-            CREATE TABLE persons (
-                id   INTEGER PRIMARY KEY,
-                name TEXT    NOT NULL
-            )
-        ";
+        settings.apply(&conn)?;
+        migrations::apply(&mut conn)?;
 
-        conn.execute(q, []).expect("failed to execute");
+        let tx = conn.transaction()?;
 
-        let q = "INSERT INTO persons (name) VALUES (?1)";
+        for q in args.schema.iter().chain(&args.seed) {
+            tx.execute_batch(q)?;
+        }
 
-        conn.execute(q, ["Or"]).expect("failed to execute");
-        conn.execute(q, ["Laura"]).expect("failed to execute");
-        conn.execute(q, ["Jacob"]).expect("failed to execute");
-        conn.execute(q, ["Sadie"]).expect("failed to execute");
-    });
+        tx.commit()?;
+
+        Ok::<_, Error>(())
+    })?;
+
+    SETTINGS.with(|m| m.borrow_mut().insert((), settings));
+
+    Ok(())
+}
+
+#[ic_cdk::inspect_message]
+fn inspect_message_fn() {
+    let method = ic_cdk::api::call::method_name();
+
+    let required = match method.as_str() {
+        // These hand out or replace the whole database, see their handlers
+        "export_begin" | "export_continue" | "export_end" | "import_begin" | "import_chunk"
+        | "import_commit" => None,
+        "execute" | "transaction" | "insert_row" | "bulk_insert" => Some(Role::Writer),
+        "grant_role" | "revoke_role" => Some(Role::Admin),
+        "restore_sql" | "register_query" | "unregister_query" => Some(Role::Admin),
+        "schedule_maintenance" => Some(Role::Admin),
+        _ => Some(Role::Reader),
+    };
+
+    let allowed = match required {
+        Some(r) => acl::authorize(r).is_ok(),
+        None => acl::authorize_controller().is_ok(),
+    };
+
+    // Rejecting here saves the cycles of executing a call that would fail anyway
+    if allowed {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) -> Result<(), Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    acl::grant(principal, role);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn revoke_role(principal: Principal) -> Result<Option<Role>, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    Ok(acl::revoke(&principal))
+}
+
+#[ic_cdk::query]
+fn list_roles() -> Result<Vec<(Principal, Role)>, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    Ok(acl::list())
 }
 
 #[ic_cdk::query]
-fn trigger_query() {
+fn trigger_query() -> Result<(), Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Reader)?;
+
     CONN.with(|conn| {
         let conn = conn.borrow_mut();
 
-        let mut stmt = conn
-            .prepare("SELECT id, name FROM persons")
-            .expect("failed to prepare statement");
+        let mut stmt = policy::prepare_cached(&conn, "SELECT id, name FROM persons")?;
 
         let f = |r: &Row| {
             Ok((
-                r.get(0).expect("failed to get column"), // id
-                r.get(1).expect("failed to get column"), // name
+                r.get(0)?, // id
+                r.get(1)?, // name
             ))
         };
 
-        let ps = stmt.query_map([], f).expect("failed to query");
+        let ps = stmt.query_map([], f)?;
         for p in ps {
-            let (id, name): (i32, String) = p.expect("failed to read row");
+            let (id, name): (i32, String) = p?;
             ic_cdk::println!("{} {}", id, name);
         }
-    });
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn schema_version() -> Result<SchemaVersion, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Reader)?;
+
+    CONN.with(|conn| {
+        Ok(SchemaVersion {
+            current: migrations::current_version(&conn.borrow())?,
+            target: migrations::target_version(),
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn query(sql: String, params: Vec<SqlValue>, page: Option<Page>) -> Result<QueryResult, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Reader)?;
+
+    CONN.with(|conn| {
+        let conn = conn.borrow_mut();
+
+        record_query(&sql, || {
+            page::query(&conn, &sql, params, &page.unwrap_or_default())
+        })
+    })
+}
+
+// Records the statement only when called as an update, the one case in
+// which the stats it adds are kept
+fn record_query(
+    sql: &str,
+    f: impl FnOnce() -> Result<QueryResult, Error>,
+) -> Result<QueryResult, Error> {
+    if !ic_cdk::api::in_replicated_execution() {
+        return f();
+    }
+
+    let start = stats::start();
+    let res = f();
+
+    stats::record(sql, start, res.as_ref().ok().map(|v| v.rows.len() as u64));
+
+    res
 }
 
 #[ic_cdk::update]
-fn insert_row(name: String) {
+fn execute(sql: String, params: Vec<SqlValue>) -> Result<ExecuteResult, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Writer)?;
+
     CONN.with(|conn| {
-        conn.borrow_mut()
-            .execute("INSERT INTO persons (name) VALUES (?1)", [&name])
-            .expect("failed to execute");
-    });
+        let conn = conn.borrow_mut();
+
+        let start = stats::start();
+
+        let params = params_from_iter(params.into_iter().map(Value::from));
+        let res = policy::prepare_cached(&conn, &sql).and_then(|mut s| s.execute(params));
+
+        stats::record(&sql, start, res.as_ref().ok().map(|_| conn.changes()));
+        res?;
+
+        Ok(ExecuteResult {
+            changes: conn.changes(),
+            last_insert_rowid: conn.last_insert_rowid(),
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn transaction(stmts: Vec<Statement>) -> Result<Vec<ExecuteResult>, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Writer)?;
+
+    CONN.with(|conn| {
+        let mut conn = conn.borrow_mut();
+
+        // Dropping the transaction without committing rolls it back
+        let tx = policy::transaction(&mut conn)?;
+
+        let mut out = vec![];
+
+        for (idx, stmt) in stmts.into_iter().enumerate() {
+            let start = stats::start();
+
+            let params = params_from_iter(stmt.params.into_iter().map(Value::from));
+
+            let res = policy::prepare_cached(&tx, &stmt.sql).and_then(|mut s| s.execute(params));
+
+            stats::record(&stmt.sql, start, res.as_ref().ok().map(|_| tx.changes()));
+
+            if let Err(err) = res {
+                return Err(Error::Transaction {
+                    index: idx as u64,
+                    error: Box::new(err.into()),
+                });
+            }
+
+            out.push(ExecuteResult {
+                changes: tx.changes(),
+                last_insert_rowid: tx.last_insert_rowid(),
+            });
+        }
+
+        tx.commit()?;
+
+        Ok(out)
+    })
+}
+
+#[ic_cdk::update]
+fn insert_row(name: String) -> Result<(), Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Writer)?;
+
+    CONN.with(|conn| {
+        let conn = conn.borrow_mut();
+
+        policy::prepare_cached(&conn, "INSERT INTO persons (name) VALUES (?1)")?
+            .execute([&name])?;
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn export_begin() -> Result<ExportStatus, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize_controller()?;
+
+    export::begin()
+}
+
+#[ic_cdk::update]
+fn export_continue() -> Result<ExportStatus, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize_controller()?;
+
+    export::step()
+}
+
+#[ic_cdk::query]
+fn export_chunk(offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize_controller()?;
+
+    export::chunk(offset, len)
+}
+
+#[ic_cdk::update]
+fn export_end() -> Result<(), Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize_controller()?;
+
+    export::end()
+}
+
+#[ic_cdk::update]
+fn import_begin(size: u64, sha256: Vec<u8>) -> Result<(), Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize_controller()?;
+
+    import::begin(size, sha256)
+}
+
+#[ic_cdk::update]
+fn import_chunk(offset: u64, bytes: Vec<u8>) -> Result<(), Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize_controller()?;
+
+    import::chunk(offset, &bytes)
+}
+
+#[ic_cdk::update]
+fn import_commit() -> Result<ImportStatus, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize_controller()?;
+
+    if let Some(status) = import::commit()? {
+        return Ok(status);
+    }
+
+    // Trap rather than return, so a failure rolls back the swap as well
+    if let Err(err) = configure() {
+        trap(&format!("failed to open imported database: {err}"));
+    }
+
+    CONN.with(|conn| {
+        Ok(ImportStatus::Committed(SchemaVersion {
+            current: migrations::current_version(&conn.borrow())?,
+            target: migrations::target_version(),
+        }))
+    })
+}
+
+#[ic_cdk::query]
+fn dump(cursor: Option<Vec<u8>>) -> Result<DumpPage, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    CONN.with(|conn| dump::dump(&conn.borrow(), cursor))
+}
+
+#[ic_cdk::update]
+fn restore_sql(script: String) -> Result<u64, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    CONN.with(|conn| dump::restore(&mut conn.borrow_mut(), &script))
+}
+
+#[ic_cdk::update]
+fn bulk_insert(table: String, format: Format, data: String) -> Result<BulkResult, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Writer)?;
+
+    CONN.with(|conn| bulk::insert(&mut conn.borrow_mut(), &table, format, &data))
+}
+
+#[ic_cdk::update]
+fn register_query(query: NamedQuery) -> Result<(), Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    CONN.with(|conn| named::register(&conn.borrow(), &query))
+}
+
+#[ic_cdk::update]
+fn unregister_query(name: String) -> Result<bool, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    CONN.with(|conn| named::unregister(&conn.borrow(), &name))
+}
+
+#[ic_cdk::query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    let _logs = polyfill::LogScope;
+
+    // Open to anyone: the gateway does not authenticate callers, so only
+    // queries an admin registered as public are served
+    CONN.with(|conn| http::handle(&conn.borrow(), req))
+}
+
+#[ic_cdk::query]
+fn list_queries() -> Result<Vec<NamedQuery>, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Reader)?;
+
+    CONN.with(|conn| named::list(&conn.borrow()))
+}
+
+#[ic_cdk::query]
+fn run_named(name: String, args: Vec<SqlValue>, page: Option<Page>) -> Result<QueryResult, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Reader)?;
+
+    CONN.with(|conn| {
+        let conn = conn.borrow();
+
+        let Some(q) = named::lookup(&conn, &name)? else {
+            return Err(Error::Validation(format!("no query named {name:?}")));
+        };
+
+        record_query(&q.sql, || {
+            named::run(&conn, &q, args, &page.unwrap_or_default())
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn statement_stats() -> Result<Vec<StatementStats>, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    Ok(stats::list())
+}
+
+#[ic_cdk::update]
+fn schedule_maintenance(task: Task, interval_secs: Option<u64>) -> Result<(), Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    maintenance::schedule(task, interval_secs)
+}
+
+#[ic_cdk::query]
+fn maintenance_status() -> Result<Vec<JobStatus>, Error> {
+    let _logs = polyfill::LogScope;
+
+    acl::authorize(Role::Admin)?;
+
+    Ok(maintenance::status())
 }
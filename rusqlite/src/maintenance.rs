@@ -0,0 +1,273 @@
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, time::Duration};
+
+use candid::{CandidType, Decode, Encode};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{storable::Bound, Storable};
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use crate::{backup, error::Error, polyfill, CONN, MAINTENANCE};
+
+// Keeps a misconfigured job from starving regular calls
+const MIN_INTERVAL_SECS: u64 = 60;
+
+// Backup chunks copied per timer message, keeping each well within the
+// instruction limit
+const BACKUP_STEP_CHUNKS: u64 = 16;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Task {
+    /// `PRAGMA optimize`
+    Optimize,
+
+    /// `PRAGMA incremental_vacuum`, only frees pages with
+    /// `auto_vacuum = INCREMENTAL`.
+    IncrementalVacuum,
+
+    /// `PRAGMA wal_checkpoint(TRUNCATE)`, a no-op outside WAL mode.
+    WalCheckpoint,
+
+    /// `ANALYZE`, refreshing the statistics used by the query planner.
+    Analyze,
+
+    /// Chunked backup of the database file into stable memory, copied a few
+    /// chunks per message until complete.
+    Backup,
+}
+
+impl Storable for Task {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(vec![*self as u8])
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        match bytes[0] {
+            0 => Task::Optimize,
+            1 => Task::IncrementalVacuum,
+            2 => Task::WalCheckpoint,
+            3 => Task::Analyze,
+            4 => Task::Backup,
+            v => panic!("invalid task {v}"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1,
+        is_fixed_size: true,
+    };
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub interval_secs: u64,
+
+    /// Time of the last run, in nanoseconds since the epoch.
+    pub last_run: Option<u64>,
+    pub last_result: Option<Result<String, Error>>,
+
+    /// Backup in progress, resumed by the next run.
+    pub(crate) backup: Option<backup::Cursor>,
+}
+
+impl Storable for Job {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode job"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to decode job")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobStatus {
+    pub task: Task,
+    pub interval_secs: u64,
+    pub last_run: Option<u64>,
+    pub last_result: Option<Result<String, Error>>,
+
+    /// Whether a timer is currently set for the job.
+    pub armed: bool,
+}
+
+thread_local! {
+    // Timers do not survive upgrades, `arm` sets them again from `MAINTENANCE`
+    static TIMERS: RefCell<BTreeMap<Task, TimerId>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Runs `task` every `interval_secs`, or stops running it if `None`.
+pub(crate) fn schedule(task: Task, interval_secs: Option<u64>) -> Result<(), Error> {
+    let Some(interval_secs) = interval_secs else {
+        MAINTENANCE.with(|m| m.borrow_mut().remove(&task));
+        disarm(task);
+
+        return Ok(());
+    };
+
+    if interval_secs < MIN_INTERVAL_SECS {
+        return Err(Error::Validation(format!(
+            "interval must be at least {MIN_INTERVAL_SECS} seconds"
+        )));
+    }
+
+    MAINTENANCE.with(|m| {
+        let mut m = m.borrow_mut();
+
+        let job = match m.get(&task) {
+            Some(v) => Job { interval_secs, ..v },
+            None => Job {
+                interval_secs,
+                last_run: None,
+                last_result: None,
+                backup: None,
+            },
+        };
+
+        m.insert(task, job);
+    });
+
+    set_timer(task, interval_secs);
+
+    Ok(())
+}
+
+/// Sets a timer for every scheduled job. Backups in progress start over,
+/// since their cursors cannot be resumed after an upgrade.
+pub(crate) fn arm() {
+    let jobs: Vec<_> = MAINTENANCE.with(|m| m.borrow().iter().collect());
+
+    for (task, job) in jobs {
+        if job.backup.is_some() {
+            let job = Job {
+                backup: None,
+                ..job.clone()
+            };
+
+            MAINTENANCE.with(|m| m.borrow_mut().insert(task, job));
+        }
+
+        set_timer(task, job.interval_secs);
+    }
+}
+
+pub(crate) fn status() -> Vec<JobStatus> {
+    let armed = |task| TIMERS.with(|t| t.borrow().contains_key(&task));
+
+    MAINTENANCE.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(task, job)| JobStatus {
+                task,
+                interval_secs: job.interval_secs,
+                last_run: job.last_run,
+                last_result: job.last_result,
+                armed: armed(task),
+            })
+            .collect()
+    })
+}
+
+fn set_timer(task: Task, interval_secs: u64) {
+    disarm(task);
+
+    let id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval_secs), move || {
+        run(task, false)
+    });
+
+    TIMERS.with(|t| t.borrow_mut().insert(task, id));
+}
+
+fn disarm(task: Task) {
+    if let Some(id) = TIMERS.with(|t| t.borrow_mut().remove(&task)) {
+        ic_cdk_timers::clear_timer(id);
+    }
+}
+
+// `follow_up` is set for the runs that carry an unfinished backup on
+fn run(task: Task, follow_up: bool) {
+    let _logs = polyfill::LogScope;
+
+    // The job may have been unscheduled in the meantime
+    let Some(job) = MAINTENANCE.with(|m| m.borrow().get(&task)) else {
+        return;
+    };
+
+    // Only the run that started a backup, or one carrying it on, sets the
+    // next follow-up, so interval runs do not start a second chain
+    let chain = follow_up || job.backup.is_none();
+
+    let mut cursor = None;
+
+    let res = match task {
+        Task::Backup => match backup::step("db.sqlite3", job.backup, BACKUP_STEP_CHUNKS) {
+            Ok(backup::Step::Partial(c)) => {
+                let (copied, total) = c.progress();
+                cursor = Some(c);
+
+                // Carries on right away rather than at the next interval
+                if chain {
+                    ic_cdk_timers::set_timer(Duration::ZERO, move || run(task, true));
+                }
+
+                Ok(format!("copied {copied} of {total} chunks"))
+            }
+            Ok(backup::Step::Done(hdr)) => {
+                Ok(format!("{} bytes in {} chunks", hdr.size, hdr.chunks))
+            }
+            Err(err) => Err(err),
+        },
+
+        task => CONN.with(|conn| run_sql(&conn.borrow(), task)),
+    };
+
+    if let Err(err) = &res {
+        ic_cdk::println!("maintenance task {task:?} failed: {err}");
+    }
+
+    let job = Job {
+        last_run: Some(ic_cdk::api::time()),
+        last_result: Some(res),
+        backup: cursor,
+        ..job
+    };
+
+    MAINTENANCE.with(|m| m.borrow_mut().insert(task, job));
+}
+
+// Timers run as the canister itself, which the authorizer treats as an admin
+fn run_sql(conn: &Connection, task: Task) -> Result<String, Error> {
+    match task {
+        Task::Optimize => conn.execute_batch("PRAGMA optimize")?,
+
+        Task::IncrementalVacuum => {
+            let free = |conn: &Connection| {
+                conn.pragma_query_value(None, "freelist_count", |r| r.get::<_, u64>(0))
+            };
+
+            let before = free(conn)?;
+            conn.execute_batch("PRAGMA incremental_vacuum")?;
+            let after = free(conn)?;
+
+            return Ok(format!("freed {} pages", before.saturating_sub(after)));
+        }
+
+        Task::WalCheckpoint => {
+            let (busy, log, checkpointed): (i64, i64, i64) =
+                conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| {
+                    Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+                })?;
+
+            return Ok(format!(
+                "busy {busy}, log {log}, checkpointed {checkpointed}"
+            ));
+        }
+
+        Task::Analyze => conn.execute_batch("ANALYZE")?,
+
+        Task::Backup => unreachable!("backups do not go through the connection"),
+    }
+
+    Ok("ok".into())
+}
@@ -0,0 +1,58 @@
+use rusqlite::Connection;
+
+use crate::{error::Error, types::SchemaVersion};
+
+// Migrations are applied in order and never edited once released; append a
+// new entry to change the schema. The position in the list, starting at 1, is
+// the `user_version` the database is at after the migration has run.
+//
+// Only tables the canister itself relies on belong here. Application tables
+// come from `InitArgs::schema`, so one build can serve different schemas.
+const MIGRATIONS: &[&str] = &[
+    // 1: named queries with their typed parameters, the public ones also
+    // served over HTTP
+    "
+    CREATE TABLE IF NOT EXISTS _named_queries (
+        name   TEXT PRIMARY KEY,
+        sql    TEXT NOT NULL,
+        params TEXT NOT NULL,
+        public INTEGER NOT NULL DEFAULT 0
+    );
+    ",
+];
+
+pub(crate) fn current_version(conn: &Connection) -> Result<u32, Error> {
+    let v = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+
+    Ok(v)
+}
+
+pub(crate) fn target_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// Applies every pending migration in a single transaction.
+pub(crate) fn apply(conn: &mut Connection) -> Result<SchemaVersion, Error> {
+    let current = current_version(conn)?;
+    let target = target_version();
+
+    if current > target {
+        return Err(Error::Validation(format!(
+            "database is at schema version {current}, newer than this canister ({target})"
+        )));
+    }
+
+    let tx = conn.transaction()?;
+
+    for (idx, m) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        tx.execute_batch(m)?;
+        tx.pragma_update(None, "user_version", idx as u32 + 1)?;
+    }
+
+    tx.commit()?;
+
+    Ok(SchemaVersion {
+        current: target,
+        target,
+    })
+}
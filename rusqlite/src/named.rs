@@ -0,0 +1,287 @@
+use candid::CandidType;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Deserialize;
+
+use crate::{
+    acl::Role,
+    error::Error,
+    page, policy,
+    types::{Page, QueryResult, SqlValue},
+};
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ParamType {
+    Integer,
+    Real,
+    Text,
+    Blob,
+    Any,
+}
+
+impl ParamType {
+    fn as_str(self) -> &'static str {
+        match self {
+            ParamType::Integer => "integer",
+            ParamType::Real => "real",
+            ParamType::Text => "text",
+            ParamType::Blob => "blob",
+            ParamType::Any => "any",
+        }
+    }
+
+    fn parse(v: &str) -> Option<Self> {
+        [
+            ParamType::Integer,
+            ParamType::Real,
+            ParamType::Text,
+            ParamType::Blob,
+            ParamType::Any,
+        ]
+        .into_iter()
+        .find(|t| t.as_str() == v)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Param {
+    pub name: String,
+    pub kind: ParamType,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NamedQuery {
+    pub name: String,
+    pub sql: String,
+
+    /// One entry per statement parameter, in order.
+    pub params: Vec<Param>,
+
+    /// Whether anyone may run the query over HTTP, without a role.
+    pub public: bool,
+}
+
+/// Registers `q`, replacing any previous query of the same name. Named
+/// queries run with the Reader role for any caller, so only read-only
+/// statements are accepted.
+pub(crate) fn register(conn: &Connection, q: &NamedQuery) -> Result<(), Error> {
+    if !is_ident(&q.name) {
+        return Err(Error::Validation(format!(
+            "invalid query name {:?}",
+            q.name
+        )));
+    }
+
+    for (i, p) in q.params.iter().enumerate() {
+        if !is_ident(&p.name) || q.params[..i].iter().any(|v| v.name == p.name) {
+            return Err(Error::Validation(format!(
+                "invalid or duplicate parameter name {:?}",
+                p.name
+            )));
+        }
+    }
+
+    policy::with_role(Role::Reader, || {
+        let stmt = policy::prepare(conn, &q.sql)?;
+
+        if !stmt.readonly() {
+            return Err(Error::Validation("named queries must be read-only".into()));
+        }
+
+        if stmt.parameter_count() != q.params.len() {
+            return Err(Error::Validation(format!(
+                "statement has {} parameters, {} declared",
+                stmt.parameter_count(),
+                q.params.len()
+            )));
+        }
+
+        // Named SQL parameters have to agree with the declaration
+        for (i, p) in (1..).zip(&q.params) {
+            match stmt.parameter_name(i) {
+                Some(v) if !v.starts_with('?') && v[1..] != p.name => {
+                    return Err(Error::Validation(format!(
+                        "parameter {i} is {v} in the statement but declared as {:?}",
+                        p.name
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    })?;
+
+    conn.execute(
+        "INSERT OR REPLACE INTO _named_queries (name, sql, params, public)
+         VALUES (?1, ?2, ?3, ?4)",
+        (&q.name, &q.sql, &format_params(&q.params), q.public),
+    )?;
+
+    Ok(())
+}
+
+/// Returns whether a query named `name` existed.
+pub(crate) fn unregister(conn: &Connection, name: &str) -> Result<bool, Error> {
+    let n = conn.execute("DELETE FROM _named_queries WHERE name = ?1", [name])?;
+
+    Ok(n > 0)
+}
+
+pub(crate) fn lookup(conn: &Connection, name: &str) -> Result<Option<NamedQuery>, Error> {
+    // The registry is a reserved table, readable only by admins
+    let q = policy::with_role(Role::Admin, || {
+        conn.query_row(
+            "SELECT name, sql, params, public FROM _named_queries WHERE name = ?1",
+            [name],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get::<_, String>(2)?, r.get(3)?)),
+        )
+        .optional()
+    })?;
+
+    q.map(|(name, sql, params, public)| {
+        Ok(NamedQuery {
+            name,
+            sql,
+            params: parse_params(&params)?,
+            public,
+        })
+    })
+    .transpose()
+}
+
+pub(crate) fn list(conn: &Connection) -> Result<Vec<NamedQuery>, Error> {
+    let rows: Vec<(String, String, String, bool)> = policy::with_role(Role::Admin, || {
+        let mut stmt =
+            conn.prepare("SELECT name, sql, params, public FROM _named_queries ORDER BY name")?;
+
+        let rows = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?
+            .collect::<Result<_, _>>()?;
+
+        Ok::<_, Error>(rows)
+    })?;
+
+    rows.into_iter()
+        .map(|(name, sql, params, public)| {
+            Ok(NamedQuery {
+                name,
+                sql,
+                params: parse_params(&params)?,
+                public,
+            })
+        })
+        .collect()
+}
+
+/// Runs `q` with the Reader role after checking `args` against its declared
+/// parameters. The statement is prepared once and kept in the connection's
+/// statement cache.
+pub(crate) fn run(
+    conn: &Connection,
+    q: &NamedQuery,
+    args: Vec<SqlValue>,
+    page: &Page,
+) -> Result<QueryResult, Error> {
+    if args.len() != q.params.len() {
+        return Err(Error::Validation(format!(
+            "{} expects {} arguments, got {}",
+            q.name,
+            q.params.len(),
+            args.len()
+        )));
+    }
+
+    let args = q
+        .params
+        .iter()
+        .zip(args)
+        .map(|(p, v)| check(p, v))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    policy::with_role(Role::Reader, || page::query(conn, &q.sql, args, page))
+}
+
+/// Converts a textual argument, e.g. from a URL, to the declared type.
+pub(crate) fn parse_arg(p: &Param, v: &str) -> Result<SqlValue, Error> {
+    let invalid = || {
+        Error::Validation(format!(
+            "parameter {:?} expects {}",
+            p.name,
+            p.kind.as_str()
+        ))
+    };
+
+    let v = match p.kind {
+        ParamType::Integer => SqlValue::Integer(v.parse().map_err(|_| invalid())?),
+        ParamType::Real => SqlValue::Real(v.parse().map_err(|_| invalid())?),
+        ParamType::Text | ParamType::Any => SqlValue::Text(v.to_owned()),
+        ParamType::Blob => {
+            if !v.len().is_multiple_of(2) {
+                return Err(invalid());
+            }
+
+            let bs = (0..v.len())
+                .step_by(2)
+                .map(|i| v.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+                .collect::<Option<_>>()
+                .ok_or_else(invalid)?;
+
+            SqlValue::Blob(bs)
+        }
+    };
+
+    Ok(v)
+}
+
+// NULL is accepted for every type; integers widen to reals
+fn check(p: &Param, v: SqlValue) -> Result<SqlValue, Error> {
+    let v = match (p.kind, v) {
+        (_, SqlValue::Null) => SqlValue::Null,
+        (ParamType::Real, SqlValue::Integer(v)) => SqlValue::Real(v as f64),
+        (ParamType::Any, v)
+        | (ParamType::Integer, v @ SqlValue::Integer(_))
+        | (ParamType::Real, v @ SqlValue::Real(_))
+        | (ParamType::Text, v @ SqlValue::Text(_))
+        | (ParamType::Blob, v @ SqlValue::Blob(_)) => v,
+        _ => {
+            return Err(Error::Validation(format!(
+                "parameter {:?} expects {}",
+                p.name,
+                p.kind.as_str()
+            )))
+        }
+    };
+
+    Ok(v)
+}
+
+fn is_ident(v: &str) -> bool {
+    !v.is_empty() && v.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Parameters are stored as text, e.g. "name text, age integer", so the
+// registry stays readable in SQL dumps
+fn format_params(ps: &[Param]) -> String {
+    ps.iter()
+        .map(|p| format!("{} {}", p.name, p.kind.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parse_params(v: &str) -> Result<Vec<Param>, Error> {
+    v.split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|p| {
+            let (name, kind) = p.split_once(' ').unwrap_or((p, ""));
+
+            let kind = ParamType::parse(kind.trim())
+                .ok_or_else(|| Error::Validation(format!("malformed parameter {p:?}")))?;
+
+            Ok(Param {
+                name: name.to_owned(),
+                kind,
+            })
+        })
+        .collect()
+}
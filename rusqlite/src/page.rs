@@ -0,0 +1,356 @@
+use candid::{CandidType, Decode, Encode};
+use rusqlite::{params_from_iter, types::Value, Connection};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    conv::quote_ident,
+    error::Error,
+    policy,
+    types::{Column, Page, QueryResult, SqlRow, SqlValue},
+    MAX_PAGE_BYTES,
+};
+
+const DEFAULT_MAX_ROWS: u32 = 1000;
+
+// Fixed per-value overhead used when estimating the encoded size of a row
+const VALUE_OVERHEAD: usize = 2;
+
+/// Continuation state handed to the client as an opaque blob. The hash ties
+/// it to the statement and parameters it was issued for.
+#[derive(CandidType, Deserialize)]
+struct Cursor {
+    /// Rows returned so far.
+    offset: u64,
+    hash: Vec<u8>,
+
+    /// First column of the last row returned, if the result is ordered by
+    /// it. The next page then seeks past this value instead of stepping over
+    /// `offset` rows.
+    after: Option<i64>,
+}
+
+/// Runs `sql` with `params` and reads one page of its result.
+///
+/// Every page runs the statement again. A statement that ends in `ORDER BY`
+/// its first column, an integer such as a rowid, resumes with a
+/// `WHERE <column> > <last value>` filter that SQLite can answer from an
+/// index; any other statement steps over the rows of earlier pages, which
+/// makes reading a large result through costly.
+pub(crate) fn query(
+    conn: &Connection,
+    sql: &str,
+    params: Vec<SqlValue>,
+    page: &Page,
+) -> Result<QueryResult, Error> {
+    let hash = hash(sql, &params);
+    let cursor = cursor(page, &hash)?;
+
+    let stmt = policy::prepare_cached(conn, sql)?;
+
+    let columns: Vec<Column> = stmt
+        .columns()
+        .into_iter()
+        .map(|c| Column {
+            name: c.name().to_owned(),
+            decl_type: c.decl_type().map(ToOwned::to_owned),
+        })
+        .collect();
+
+    let key = keyset_column(sql, &columns);
+    let offset = cursor.as_ref().map_or(0, |c| c.offset);
+
+    let (mut stmt, params, skip) = match (key, cursor.and_then(|c| c.after)) {
+        (Some(key), Some(after)) => {
+            let sql = format!(
+                "SELECT * FROM ({}) WHERE {} > ?{}",
+                sql.trim_end().trim_end_matches(';'),
+                quote_ident(key),
+                stmt.parameter_count() + 1
+            );
+
+            let mut params = params;
+            params.push(SqlValue::Integer(after));
+
+            (policy::prepare_cached(conn, &sql)?, params, 0)
+        }
+        _ => (stmt, params, offset),
+    };
+
+    let mut rs = stmt.query(params_from_iter(params.into_iter().map(Value::from)))?;
+
+    for _ in 0..skip {
+        if rs.next()?.is_none() {
+            return Ok(QueryResult {
+                columns,
+                rows: vec![],
+                next: None,
+            });
+        }
+    }
+
+    let max_rows = page.max_rows.unwrap_or(DEFAULT_MAX_ROWS).max(1) as usize;
+
+    let mut rows: Vec<SqlRow> = vec![];
+    let mut bytes = 0;
+
+    // First row of the next page, read to learn whether there is one
+    let mut more = None;
+
+    while let Some(r) = rs.next()? {
+        let row: SqlRow = (0..columns.len())
+            .map(|i| r.get_ref(i).map(SqlValue::from))
+            .collect::<Result<_, _>>()?;
+
+        let size = row.iter().map(size_of).sum::<usize>();
+
+        if size > MAX_PAGE_BYTES {
+            return Err(Error::RowTooLarge {
+                size: size as u64,
+                limit: MAX_PAGE_BYTES as u64,
+            });
+        }
+
+        if rows.len() >= max_rows || bytes + size > MAX_PAGE_BYTES {
+            more = Some(row);
+            break;
+        }
+
+        bytes += size;
+        rows.push(row);
+    }
+
+    let next = more.map(|first| {
+        let c = Cursor {
+            offset: offset + rows.len() as u64,
+            hash,
+            after: key.and(rows.last()).and_then(|last| boundary(last, &first)),
+        };
+
+        Encode!(&c).expect("failed to encode cursor")
+    });
+
+    Ok(QueryResult {
+        columns,
+        rows,
+        next,
+    })
+}
+
+fn hash(sql: &str, params: &[SqlValue]) -> Vec<u8> {
+    let bs = Encode!(&sql, &params).expect("failed to encode query");
+
+    Sha256::digest(bs).to_vec()
+}
+
+fn cursor(page: &Page, hash: &[u8]) -> Result<Option<Cursor>, Error> {
+    let Some(bs) = &page.cursor else {
+        return Ok(None);
+    };
+
+    let c = Decode!(bs, Cursor).map_err(|_| Error::Validation("malformed cursor".into()))?;
+
+    if c.hash != hash {
+        return Err(Error::Validation(
+            "cursor was issued for a different query".into(),
+        ));
+    }
+
+    Ok(Some(c))
+}
+
+// Name of the first column if `sql` ends in `ORDER BY` that column. Columns
+// declared with a type other than an integer are left out, since their
+// affinity could turn the comparison with the last value into a text one.
+fn keyset_column<'a>(sql: &str, columns: &'a [Column]) -> Option<&'a str> {
+    let first = columns.first()?;
+
+    if first
+        .decl_type
+        .as_ref()
+        .is_some_and(|t| !t.to_ascii_uppercase().contains("INT"))
+    {
+        return None;
+    }
+
+    // A trailing comment could hide what really ends the statement
+    if sql.contains("--") || sql.contains("/*") {
+        return None;
+    }
+
+    let mut tokens = sql
+        .trim_end()
+        .trim_end_matches(';')
+        .split_ascii_whitespace()
+        .rev()
+        .peekable();
+
+    tokens.next_if(|t| t.eq_ignore_ascii_case("ASC"));
+
+    let (name, by, order) = (tokens.next()?, tokens.next()?, tokens.next()?);
+
+    let matches = match name.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(v) => v.replace("\"\"", "\"") == first.name,
+        None => name.eq_ignore_ascii_case(&first.name),
+    };
+
+    (matches && by.eq_ignore_ascii_case("BY") && order.eq_ignore_ascii_case("ORDER"))
+        .then_some(first.name.as_str())
+}
+
+// Where the next page starts, if it can seek there: rows with an equal first
+// column would straddle the two pages.
+fn boundary(last: &SqlRow, next: &SqlRow) -> Option<i64> {
+    let SqlValue::Integer(v) = last[0] else {
+        return None;
+    };
+
+    match next[0] {
+        SqlValue::Integer(n) if n > v => Some(v),
+        SqlValue::Real(n) if n > v as f64 => Some(v),
+        SqlValue::Text(_) | SqlValue::Blob(_) => Some(v),
+        _ => None,
+    }
+}
+
+fn size_of(v: &SqlValue) -> usize {
+    VALUE_OVERHEAD
+        + match v {
+            SqlValue::Null => 0,
+            SqlValue::Integer(_) | SqlValue::Real(_) => 8,
+            SqlValue::Text(v) => v.len(),
+            SqlValue::Blob(v) => v.len(),
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Role;
+
+    fn open() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, g INTEGER, v TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2500)
+             INSERT INTO t SELECT i, i / 3, 'row ' || i FROM n;",
+        )
+        .unwrap();
+
+        conn
+    }
+
+    // Reads every page, returning the rows and the cursors handed out
+    fn read_all(conn: &Connection, sql: &str, max_rows: u32) -> (Vec<SqlRow>, Vec<Cursor>) {
+        policy::with_role(Role::Reader, || {
+            let mut page = Page {
+                cursor: None,
+                max_rows: Some(max_rows),
+            };
+
+            let mut rows = vec![];
+            let mut cursors = vec![];
+
+            loop {
+                let res = query(conn, sql, vec![], &page).unwrap();
+                rows.extend(res.rows);
+
+                let Some(next) = res.next else {
+                    return (rows, cursors);
+                };
+
+                cursors.push(Decode!(&next, Cursor).unwrap());
+                page.cursor = Some(next);
+            }
+        })
+    }
+
+    fn ids(rows: &[SqlRow], col: usize) -> Vec<i64> {
+        rows.iter()
+            .map(|r| match r[col] {
+                SqlValue::Integer(v) => v,
+                ref v => panic!("unexpected {v:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seeks_when_ordered_by_first_column() {
+        let conn = open();
+
+        let (rows, cursors) = read_all(&conn, "SELECT id, v FROM t ORDER BY id;", 100);
+
+        assert_eq!(ids(&rows, 0), (1..=2500).collect::<Vec<_>>());
+        assert_eq!(cursors.len(), 24);
+        assert!(cursors.iter().all(|c| c.after.is_some()));
+    }
+
+    #[test]
+    fn seek_uses_the_primary_key() {
+        let conn = open();
+
+        let plan: String = conn
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT * FROM (SELECT id, v FROM t ORDER BY id) WHERE \"id\" > ?1",
+                [10],
+                |r| r.get(3),
+            )
+            .unwrap();
+
+        assert!(
+            plan.starts_with("SEARCH t USING INTEGER PRIMARY KEY"),
+            "{plan}"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_offset_when_unordered() {
+        let conn = open();
+
+        let (rows, cursors) = read_all(&conn, "SELECT v, id FROM t", 100);
+
+        assert_eq!(ids(&rows, 1), (1..=2500).collect::<Vec<_>>());
+        assert!(cursors.iter().all(|c| c.after.is_none()));
+    }
+
+    #[test]
+    fn keeps_equal_keys_together() {
+        let conn = open();
+
+        // Groups of three rows share a key, so some page boundaries fall
+        // inside a group and have to use the offset instead
+        let (rows, cursors) = read_all(&conn, "SELECT g, id FROM t ORDER BY g", 100);
+
+        assert_eq!(ids(&rows, 1), (1..=2500).collect::<Vec<_>>());
+        assert!(cursors.iter().any(|c| c.after.is_some()));
+        assert!(cursors.iter().any(|c| c.after.is_none()));
+    }
+
+    #[test]
+    fn limits_page_bytes() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE b (x BLOB);
+             INSERT INTO b VALUES (zeroblob(600000)), (zeroblob(600000)), (zeroblob(600000));",
+        )
+        .unwrap();
+
+        let (rows, cursors) = read_all(&conn, "SELECT x FROM b", 100);
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(cursors.len(), 1);
+    }
+
+    #[test]
+    fn rejects_oversized_row() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        let err = policy::with_role(Role::Reader, || {
+            let sql = format!("SELECT zeroblob({})", MAX_PAGE_BYTES);
+            query(&conn, &sql, vec![], &Page::default()).unwrap_err()
+        });
+
+        assert!(matches!(err, Error::RowTooLarge { .. }));
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    cell::{Cell, RefCell},
+    ops::Deref,
+};
+
+use rusqlite::{
+    hooks::{AuthAction, AuthContext, Authorization},
+    CachedStatement, Connection, Statement,
+};
+
+use crate::acl::{self, Role};
+
+thread_local! {
+    // Why the last statement was denied, picked up when its error is converted
+    static DENIAL: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // Role statements are checked against instead of the caller's, see `with_role`
+    static ROLE: Cell<Option<Role>> = const { Cell::new(None) };
+}
+
+/// Authorizer installed on `CONN`, consulted while statements are prepared.
+pub(crate) fn authorizer(ctx: AuthContext<'_>) -> Authorization {
+    match check(effective_role(), &ctx.action) {
+        Ok(()) => Authorization::Allow,
+        Err(reason) => {
+            DENIAL.with(|d| d.replace(Some(reason)));
+            Authorization::Deny
+        }
+    }
+}
+
+/// Runs `f` with statements authorized as `role`, regardless of the caller.
+/// Used for statements an admin vetted in advance, such as named queries.
+pub(crate) fn with_role<T>(role: Role, f: impl FnOnce() -> T) -> T {
+    let prev = ROLE.with(|r| r.replace(Some(role)));
+    let _restore = scopeguard::guard(prev, |prev| ROLE.with(|r| r.set(prev)));
+
+    f()
+}
+
+/// Prepares `sql` through the connection's statement cache. The authorizer
+/// only runs when a statement is prepared, so the cache key starts with a
+/// comment naming the role, keeping statements authorized for one role from
+/// being reused by another.
+pub(crate) fn prepare_cached<'a>(
+    conn: &'a Connection,
+    sql: &str,
+) -> rusqlite::Result<CachedStatement<'a>> {
+    let key = match effective_role() {
+        Some(r) => format!("/* {r:?} */ {sql}"),
+        None => format!("/* none */ {sql}"),
+    };
+
+    take_denial();
+
+    conn.prepare_cached(&key)
+}
+
+/// Prepares `sql` without caching it, for statements run only once.
+pub(crate) fn prepare<'a>(conn: &'a Connection, sql: &str) -> rusqlite::Result<Statement<'a>> {
+    take_denial();
+
+    conn.prepare(sql)
+}
+
+/// Transaction begun, committed and rolled back as Admin, since statements
+/// of callers below Admin may not control transactions themselves.
+/// Dropping it without committing rolls it back.
+pub(crate) struct Transaction<'a>(Option<rusqlite::Transaction<'a>>);
+
+impl Transaction<'_> {
+    pub(crate) fn commit(mut self) -> rusqlite::Result<()> {
+        let tx = self.0.take().expect("transaction already finished");
+
+        with_role(Role::Admin, || tx.commit())
+    }
+}
+
+impl<'a> Deref for Transaction<'a> {
+    type Target = rusqlite::Transaction<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("transaction already finished")
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if let Some(tx) = self.0.take() {
+            let _ = with_role(Role::Admin, || tx.rollback());
+        }
+    }
+}
+
+pub(crate) fn transaction(conn: &mut Connection) -> rusqlite::Result<Transaction<'_>> {
+    let tx = with_role(Role::Admin, || conn.transaction())?;
+
+    Ok(Transaction(Some(tx)))
+}
+
+pub(crate) fn take_denial() -> Option<String> {
+    DENIAL.with(|d| d.take())
+}
+
+fn effective_role() -> Option<Role> {
+    ROLE.with(|r| r.get())
+        .or_else(|| acl::role_of(&ic_cdk::caller()))
+}
+
+fn check(role: Option<Role>, action: &AuthAction<'_>) -> Result<(), String> {
+    let role = match role {
+        Some(Role::Admin) => return Ok(()),
+        Some(v) => v,
+        None => return Err("caller has no role".into()),
+    };
+
+    match *action {
+        AuthAction::Select | AuthAction::Recursive | AuthAction::Function { .. } => Ok(()),
+
+        // A batch could otherwise commit halfway, or leave `CONN` inside a
+        // transaction after the call returns
+        AuthAction::Transaction { .. } | AuthAction::Savepoint { .. } => {
+            Err("transaction control requires the Admin role".into())
+        }
+
+        AuthAction::Read { table_name, .. } => {
+            if is_reserved(table_name) && !is_schema(table_name) {
+                return Err(format!("reading {table_name} requires the Admin role"));
+            }
+
+            Ok(())
+        }
+
+        AuthAction::Insert { table_name }
+        | AuthAction::Update { table_name, .. }
+        | AuthAction::Delete { table_name } => {
+            // DDL shows up as a write to the schema table before anything else
+            if is_schema(table_name) {
+                return Err("schema changes require the Admin role".into());
+            }
+
+            if is_reserved(table_name) {
+                return Err(format!("writing to {table_name} requires the Admin role"));
+            }
+
+            if role < Role::Writer {
+                return Err(format!("writing to {table_name} requires the Writer role"));
+            }
+
+            Ok(())
+        }
+
+        AuthAction::Pragma {
+            pragma_name,
+            pragma_value,
+        } => {
+            if pragma_value.is_some() || pragma_name.eq_ignore_ascii_case("writable_schema") {
+                return Err(format!(
+                    "setting PRAGMA {pragma_name} requires the Admin role"
+                ));
+            }
+
+            Ok(())
+        }
+
+        // VACUUM is never authorized as such, but attaches a scratch database
+        // before it rewrites the file
+        AuthAction::Attach { .. } => Err("ATTACH and VACUUM require the Admin role".into()),
+        AuthAction::Detach { .. } => Err("DETACH requires the Admin role".into()),
+
+        // DDL and everything else
+        _ => Err(format!("{action:?} requires the Admin role")),
+    }
+}
+
+// Tables used by SQLite itself or by the canister
+fn is_reserved(table: &str) -> bool {
+    table.starts_with("sqlite_") || table.starts_with('_')
+}
+
+fn is_schema(table: &str) -> bool {
+    table == "sqlite_master" || table == "sqlite_schema"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::error::Error;
+
+    fn open() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (x); CREATE TABLE _private (x);")
+            .unwrap();
+        conn.authorizer(Some(authorizer));
+
+        conn
+    }
+
+    fn run(conn: &Connection, sql: &str) -> Result<(), Error> {
+        prepare_cached(conn, sql)?.raw_execute()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn denies_vacuum_below_admin() {
+        let conn = open();
+
+        with_role(Role::Writer, || {
+            let err = run(&conn, "VACUUM").unwrap_err();
+            assert!(matches!(err, Error::Denied(v) if v.contains("VACUUM")));
+
+            let err = run(&conn, "/* x */ vacuum main").unwrap_err();
+            assert!(matches!(err, Error::Denied(_)));
+        });
+
+        with_role(Role::Admin, || run(&conn, "VACUUM")).unwrap();
+    }
+
+    #[test]
+    fn denies_transaction_control_below_admin() {
+        let mut conn = open();
+
+        with_role(Role::Writer, || {
+            for sql in ["BEGIN", "COMMIT", "ROLLBACK", "SAVEPOINT s", "RELEASE s"] {
+                let err = run(&conn, sql).unwrap_err();
+                assert!(
+                    matches!(err, Error::Denied(v) if v.contains("Admin")),
+                    "{sql}"
+                );
+            }
+
+            // A batch ends at the first failure and is rolled back as a whole
+            let tx = transaction(&mut conn).unwrap();
+            run(&tx, "INSERT INTO t VALUES (1)").unwrap();
+            assert!(run(&tx, "COMMIT").is_err());
+            drop(tx);
+
+            let tx = transaction(&mut conn).unwrap();
+            run(&tx, "INSERT INTO t VALUES (2)").unwrap();
+            tx.commit().unwrap();
+        });
+
+        assert!(conn.is_autocommit());
+
+        let rows: Vec<i64> = with_role(Role::Admin, || {
+            let mut stmt = prepare(&conn, "SELECT x FROM t").unwrap();
+            let rows = stmt.query_map([], |r| r.get(0)).unwrap();
+
+            rows.collect::<Result<_, _>>().unwrap()
+        });
+        assert_eq!(rows, vec![2]);
+
+        with_role(Role::Admin, || run(&conn, "BEGIN")).unwrap();
+    }
+
+    #[test]
+    fn clears_stale_denials() {
+        let conn = open();
+
+        with_role(Role::Reader, || {
+            // Denied while preparing, and never converted to an `Error`
+            assert!(conn.prepare("SELECT * FROM _private").is_err());
+
+            // The next statement starts with no denial to pick up
+            run(&conn, "SELECT * FROM t").unwrap();
+            assert_eq!(take_denial(), None);
+
+            let err = run(&conn, "INSERT INTO t VALUES (1)").unwrap_err();
+            assert!(matches!(err, Error::Denied(v) if v.contains("Writer")));
+        });
+    }
+
+    #[test]
+    fn caches_statements_per_role() {
+        let conn = open();
+        let sql = "INSERT INTO t VALUES (1)";
+
+        // Counts how often statements are authorized, that is prepared
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+            c.fetch_add(1, Ordering::Relaxed);
+            authorizer(ctx)
+        }));
+
+        with_role(Role::Writer, || run(&conn, sql)).unwrap();
+        with_role(Role::Admin, || run(&conn, sql)).unwrap();
+
+        let prepared = calls.load(Ordering::Relaxed);
+
+        // Alternating roles reuse the statements cached for each
+        with_role(Role::Writer, || run(&conn, sql)).unwrap();
+        with_role(Role::Admin, || run(&conn, sql)).unwrap();
+        with_role(Role::Writer, || run(&conn, sql)).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), prepared);
+
+        let err = with_role(Role::Reader, || run(&conn, sql)).unwrap_err();
+        assert!(matches!(err, Error::Denied(_)));
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    cell::{Cell, RefCell},
     slice::{from_raw_parts, from_raw_parts_mut},
     str::from_utf8_unchecked,
 };
@@ -16,9 +17,11 @@ use wasi_shim::wasi::{
     Advice, Ciovec, Clockid, Dircookie, Dirent, Errno, Event, Exitcode, Fd, Fdflags, Fdstat,
     Filedelta, Filesize, Filestat, Filetype, Fstflags, Iovec, Lookupflags, Oflags, Prestat,
     PrestatDir, PrestatU, Riflags, Rights, Roflags, Sdflags, Siflags, Signal, Size, Subscription,
-    Timestamp, Whence, DIRCOOKIE_START, ERRNO_BADF, ERRNO_INVAL, ERRNO_NOTSUP, ERRNO_SUCCESS,
-    FD_STDERR, FILETYPE_DIRECTORY, FILETYPE_REGULAR_FILE, FILETYPE_SYMBOLIC_LINK, FSTFLAGS_ATIM,
-    FSTFLAGS_ATIM_NOW, FSTFLAGS_MTIM, FSTFLAGS_MTIM_NOW, WHENCE_CUR, WHENCE_END, WHENCE_SET,
+    Timestamp, Whence, CLOCKID_MONOTONIC, CLOCKID_PROCESS_CPUTIME_ID, CLOCKID_REALTIME,
+    CLOCKID_THREAD_CPUTIME_ID, DIRCOOKIE_START, ERRNO_BADF, ERRNO_INVAL, ERRNO_NOTSUP,
+    ERRNO_SUCCESS, FD_STDERR, FD_STDIN, FD_STDOUT, FILETYPE_DIRECTORY, FILETYPE_REGULAR_FILE,
+    FILETYPE_SYMBOLIC_LINK, FSTFLAGS_ATIM, FSTFLAGS_ATIM_NOW, FSTFLAGS_MTIM, FSTFLAGS_MTIM_NOW,
+    WHENCE_CUR, WHENCE_END, WHENCE_SET,
 };
 
 const ERRNO_NOP: Errno = ERRNO_SUCCESS;
@@ -30,50 +33,124 @@ const DIRCOOKIE_NEG_ONE: i64 = -1;
 const NANOSECOND: u64 = 1;
 const MICROSECOND: u64 = 1000 * NANOSECOND;
 const MILLISECOND: u64 = 1000 * MICROSECOND;
-const SECOND: u64 = 1000 * MILLISECOND;
 
-use crate::{conv, FILESYSTEM};
+// Longest stdout or stderr line kept buffered before it is printed
+const MAX_LOG_LINE: usize = 4096;
 
-pub fn args_get(_argv: *mut *mut u8, _argv_buf: *mut u8) -> Errno {
-    ERRNO_NOP
+use crate::{config::Environment, conv, FILESYSTEM};
+
+thread_local! {
+    // Last value handed out by the monotonic clock
+    static MONOTONIC: Cell<u64> = const { Cell::new(0) };
+
+    // Output not yet terminated by a newline
+    static STDOUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    static STDERR: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
 }
 
-pub fn args_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno {
-    unsafe {
-        *rp0 = 0;
-        *rp1 = 0;
-    }
+pub fn args_get(argv: *mut *mut u8, argv_buf: *mut u8) -> Errno {
+    strings_get(&args(), argv, argv_buf)
+}
 
-    ERRNO_NOP
+pub fn args_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno {
+    strings_sizes_get(&args(), rp0, rp1)
 }
 
-pub fn clock_res_get(_id: Clockid, rp0: *mut Timestamp) -> Errno {
-    #[allow(clippy::identity_op)]
+pub fn clock_res_get(id: Clockid, rp0: *mut Timestamp) -> Errno {
+    let res = match id {
+        // IC time is fixed for the duration of a message and only advances
+        // between rounds
+        CLOCKID_REALTIME | CLOCKID_MONOTONIC => MILLISECOND,
+
+        CLOCKID_PROCESS_CPUTIME_ID | CLOCKID_THREAD_CPUTIME_ID => NANOSECOND,
+
+        _ => return ERRNO_INVAL,
+    };
+
     unsafe {
-        *rp0 = 1 * SECOND;
+        *rp0 = res;
     }
 
     ERRNO_SUCCESS
 }
 
-pub fn clock_time_get(_id: Clockid, _precision: Timestamp, rp0: *mut Timestamp) -> Errno {
+pub fn clock_time_get(id: Clockid, _precision: Timestamp, rp0: *mut Timestamp) -> Errno {
+    let t = match id {
+        CLOCKID_REALTIME => ic_cdk::api::time(),
+
+        // Replicas answering queries may lag behind the last time seen
+        CLOCKID_MONOTONIC => MONOTONIC.with(|m| {
+            let t = m.get().max(ic_cdk::api::time());
+            m.set(t);
+            t
+        }),
+
+        // Instructions are reported as nanoseconds. The process clock counts
+        // the whole call context, the thread clock only the current message
+        // execution, which restarts after every await.
+        CLOCKID_PROCESS_CPUTIME_ID => ic_cdk::api::performance_counter(1),
+        CLOCKID_THREAD_CPUTIME_ID => ic_cdk::api::performance_counter(0),
+
+        _ => return ERRNO_INVAL,
+    };
+
     unsafe {
-        *rp0 = ic_cdk::api::time();
+        *rp0 = t;
     }
 
     ERRNO_SUCCESS
 }
 
 pub fn environ_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno {
+    strings_sizes_get(&vars(), rp0, rp1)
+}
+
+pub fn environ_get(environ: *mut *mut u8, environ_buf: *mut u8) -> Errno {
+    strings_get(&vars(), environ, environ_buf)
+}
+
+// Arguments and variables are handed out as NUL-terminated strings
+fn args() -> Vec<Vec<u8>> {
+    Environment::load()
+        .argv
+        .into_iter()
+        .map(|v| [v.as_bytes(), b"\0"].concat())
+        .collect()
+}
+
+fn vars() -> Vec<Vec<u8>> {
+    Environment::load()
+        .vars
+        .into_iter()
+        .map(|(k, v)| format!("{k}={v}\0").into_bytes())
+        .collect()
+}
+
+fn strings_sizes_get(strs: &[Vec<u8>], rp0: *mut Size, rp1: *mut Size) -> Errno {
     unsafe {
-        *rp0 = 0;
-        *rp1 = 0;
+        *rp0 = strs.len();
+        *rp1 = strs.iter().map(Vec::len).sum();
     }
 
     ERRNO_SUCCESS
 }
 
-pub fn environ_get(_environ: *mut *mut u8, _environ_buf: *mut u8) -> Errno {
+// Copies `strs` back to back into `buf`, which the caller sized with
+// `strings_sizes_get`, and points each entry of `ptrs` at its string
+fn strings_get(strs: &[Vec<u8>], ptrs: *mut *mut u8, buf: *mut u8) -> Errno {
+    let mut offset = 0;
+
+    for (i, s) in strs.iter().enumerate() {
+        unsafe {
+            let dst = buf.add(offset);
+            dst.copy_from_nonoverlapping(s.as_ptr(), s.len());
+
+            *ptrs.add(i) = dst;
+        }
+
+        offset += s.len();
+    }
+
     ERRNO_SUCCESS
 }
 
@@ -299,7 +376,8 @@ pub fn fd_pwrite(
     rp0: *mut Size,
 ) -> Errno {
     if fd <= FD_STDERR {
-        return ERRNO_NOP;
+        // Streams have no offset, so this is a plain write
+        return fd_write(fd, iovs, iovs_len, rp0);
     }
 
     let src = unsafe {
@@ -357,75 +435,98 @@ pub fn fd_readdir(fd: Fd, buf: *mut u8, buf_len: Size, cookie: Dircookie, rp0: *
     FILESYSTEM.with(|fs| {
         let fs = fs.borrow();
 
-        let md = match FILESYSTEM.with(|fs| fs.borrow().metadata(fd)) {
+        let md = match fs.metadata(fd) {
             Ok(v) => v,
             Err(err) => return conv::error(err),
         };
 
+        // Entry indices start at 1, so a cookie is the index of the entry to
+        // resume from and never collides with DIRCOOKIE_START
         let mut idx = match cookie {
             DIRCOOKIE_START => md.first_dir_entry,
             _ => Some(cookie as StableFsDirEntryIndex),
         };
 
-        let buf = unsafe {
-            from_raw_parts_mut(
-                buf,     // data
-                buf_len, // len
-            )
-        };
+        let entries = std::iter::from_fn(|| {
+            let i = idx.take()?;
 
-        let mut out: usize = 0;
-
-        while let Some(_idx) = idx {
-            let e = match fs.get_direntry(fd, _idx) {
+            let e = match fs.get_direntry(fd, i) {
                 Ok(v) => v,
-                Err(err) => return conv::error(err),
+                Err(err) => return Some(Err(conv::error(err))),
             };
 
             let ftype = match fs.metadata_from_node(e.node) {
                 Ok(v) => v.file_type,
-                Err(err) => return conv::error(err),
+                Err(err) => return Some(Err(conv::error(err))),
             };
 
-            let p = Dirent {
-                d_next: e.next_entry.map(Into::into).unwrap_or(u64::MAX),
-                d_ino: _idx as u64,
+            idx = e.next_entry;
+
+            let d = Dirent {
+                d_next: e.next_entry.map_or(DIRCOOKIE_NEG_ONE as u64, Into::into),
+                d_ino: i as u64,
                 d_namlen: e.name.length as u32,
                 d_type: convert_filetype(ftype),
             };
 
-            let p: *const Dirent = &p;
-            let p: *const u8 = p as *const u8;
-
-            let p = unsafe {
-                from_raw_parts(
-                    p,                   // data
-                    size_of::<Dirent>(), // len
-                )
-            };
-
-            let n = p.len().min(buf.len());
-            buf[0..n].copy_from_slice(&p[0..n]);
+            Some(Ok((d, e.name.bytes[..e.name.length as usize].to_vec())))
+        });
 
-            let fname = &e.name.bytes[0..e.name.length as usize];
-            let m = fname.len().min(buf.len());
-            buf[0..m].copy_from_slice(&fname[0..m]);
+        let buf = unsafe {
+            from_raw_parts_mut(
+                buf,     // data
+                buf_len, // len
+            )
+        };
 
-            out += n + m;
+        match pack_dirents(buf, entries) {
+            Ok(n) => {
+                unsafe {
+                    *rp0 = n;
+                }
 
-            if out >= buf_len {
-                break;
+                ERRNO_SUCCESS
             }
+            Err(err) => err,
+        }
+    })
+}
 
-            idx = e.next_entry;
+const DIRENT_SIZE: usize = size_of::<Dirent>();
+
+/// Lays out `entries` back to back in `buf`, each a `Dirent` followed by its
+/// name, and returns the number of bytes used. An entry that does not fit is
+/// cut off at the end of the buffer, so a full buffer tells the caller there
+/// may be more to read, starting from the cookie of the last whole entry.
+fn pack_dirents<N: AsRef<[u8]>>(
+    buf: &mut [u8],
+    entries: impl Iterator<Item = Result<(Dirent, N), Errno>>,
+) -> Result<usize, Errno> {
+    let mut out = 0;
+
+    for e in entries {
+        if out == buf.len() {
+            break;
         }
 
-        unsafe {
-            *rp0 = out;
+        let (d, name) = e?;
+        let name = name.as_ref();
+
+        // Written field by field, the struct's padding is not initialized
+        let mut hdr = [0u8; DIRENT_SIZE];
+        hdr[0..8].copy_from_slice(&d.d_next.to_le_bytes());
+        hdr[8..16].copy_from_slice(&d.d_ino.to_le_bytes());
+        hdr[16..20].copy_from_slice(&d.d_namlen.to_le_bytes());
+        hdr[20] = d.d_type.raw();
+
+        for part in [&hdr[..], name] {
+            let n = part.len().min(buf.len() - out);
+            buf[out..out + n].copy_from_slice(&part[..n]);
+            out += n;
         }
+    }
 
-        ERRNO_SUCCESS
-    })
+    Ok(out)
 }
 
 pub fn fd_renumber(fd: Fd, to: Fd) -> Errno {
@@ -485,8 +586,25 @@ pub fn fd_tell(fd: Fd, rp0: *mut Filesize) -> Errno {
 }
 
 pub fn fd_write(fd: Fd, iovs: *const Iovec, iovs_len: i32, rp0: *mut Size) -> Errno {
+    if fd == FD_STDIN {
+        return ERRNO_BADF;
+    }
+
     if fd <= FD_STDERR {
-        return ERRNO_NOP;
+        let src = unsafe {
+            from_raw_parts(
+                iovs as *const Ciovec, // data
+                iovs_len as usize,     // len
+            )
+        };
+
+        let n = log_write(fd, src);
+
+        unsafe {
+            *rp0 = n;
+        }
+
+        return ERRNO_SUCCESS;
     }
 
     let src = unsafe {
@@ -508,6 +626,84 @@ pub fn fd_write(fd: Fd, iovs: *const Iovec, iovs_len: i32, rp0: *mut Size) -> Er
     ERRNO_SUCCESS
 }
 
+// Appends to the stdout or stderr buffer and prints every completed line to
+// the canister log, returning the number of bytes consumed
+fn log_write(fd: Fd, src: &[Ciovec]) -> usize {
+    let buf = if fd == FD_STDOUT { &STDOUT } else { &STDERR };
+
+    buf.with(|b| {
+        let mut b = b.borrow_mut();
+        let mut n = 0;
+
+        for iov in src {
+            if iov.buf_len == 0 {
+                continue;
+            }
+
+            b.extend_from_slice(unsafe { from_raw_parts(iov.buf, iov.buf_len) });
+            n += iov.buf_len;
+        }
+
+        let mut start = 0;
+
+        while let Some(i) = b[start..].iter().position(|&c| c == b'\n') {
+            log_line(fd, &b[start..start + i]);
+            start += i + 1;
+        }
+
+        // Overlong lines are printed in pieces rather than held indefinitely
+        while b.len() - start >= MAX_LOG_LINE {
+            log_line(fd, &b[start..start + MAX_LOG_LINE]);
+            start += MAX_LOG_LINE;
+        }
+
+        b.drain(..start);
+
+        n
+    })
+}
+
+/// Prints whatever is left of an unterminated stdout or stderr line.
+pub(crate) fn flush_logs() {
+    for (fd, buf) in [(FD_STDOUT, &STDOUT), (FD_STDERR, &STDERR)] {
+        let rest = buf.with(|b| std::mem::take(&mut *b.borrow_mut()));
+
+        if !rest.is_empty() {
+            log_line(fd, &rest);
+        }
+    }
+}
+
+/// Flushes the log buffers when dropped. Every entry point holds one, since
+/// the heap of a query is discarded and the next message may never write.
+pub(crate) struct LogScope;
+
+impl Drop for LogScope {
+    fn drop(&mut self) {
+        flush_logs();
+    }
+}
+
+/// Flushes the log buffers before the panic hook installed by `ic_cdk` traps.
+pub(crate) fn hook_panics() {
+    let prev = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        flush_logs();
+        prev(info)
+    }));
+}
+
+fn log_line(fd: Fd, line: &[u8]) {
+    let line = String::from_utf8_lossy(line);
+
+    if fd == FD_STDERR {
+        ic_cdk::println!("[stderr] {line}");
+    } else {
+        ic_cdk::println!("{line}");
+    }
+}
+
 pub fn path_create_directory(fd: Fd, path: *const u8, path_len: i32) -> Errno {
     let dirname = unsafe {
         from_utf8_unchecked(from_raw_parts(
@@ -834,10 +1030,6 @@ pub fn proc_raise(_sig: Signal) -> Errno {
     ERRNO_NOTSUP
 }
 
-pub fn random_get(_buf: *mut u8, _buf_len: Size) -> Errno {
-    ERRNO_SUCCESS
-}
-
 pub fn sched_yield() -> Errno {
     ERRNO_NOP
 }
@@ -878,3 +1070,196 @@ fn convert_filetype(ftype: StableFsFileType) -> Filetype {
         StableFsFileType::SymbolicLink => FILETYPE_SYMBOLIC_LINK,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use stable_fs::storage::transient::TransientStorage;
+
+    use super::*;
+
+    // Replaces the canister's filesystem with a transient one holding a
+    // regular file for each of `names`, in order, and returns its root
+    fn populate(names: &[String]) -> Fd {
+        let mut fs = fs::FileSystem::new(Box::new(TransientStorage::new())).unwrap();
+        let root = fs.root_fd();
+
+        for name in names {
+            let fd = fs
+                .open(root, name, FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+        }
+
+        FILESYSTEM.with(|f| f.replace(fs));
+
+        root
+    }
+
+    fn readdir(fd: Fd, cookie: Dircookie, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+
+        assert_eq!(
+            fd_readdir(fd, buf.as_mut_ptr(), buf.len(), cookie, &mut n),
+            ERRNO_SUCCESS
+        );
+
+        n
+    }
+
+    // Reads whole entries from `buf` and returns their names and types, and
+    // the cookie following the last one
+    fn unpack(buf: &[u8]) -> (Vec<(String, u8)>, Option<Dircookie>) {
+        let mut entries = Vec::new();
+        let mut next = None;
+
+        let mut rest = buf;
+        while rest.len() >= DIRENT_SIZE {
+            let d_next = u64::from_le_bytes(rest[0..8].try_into().unwrap());
+            let namlen = u32::from_le_bytes(rest[16..20].try_into().unwrap()) as usize;
+
+            if rest.len() < DIRENT_SIZE + namlen {
+                break;
+            }
+
+            let name = &rest[DIRENT_SIZE..DIRENT_SIZE + namlen];
+            entries.push((String::from_utf8(name.to_vec()).unwrap(), rest[20]));
+            next = Some(d_next);
+
+            rest = &rest[DIRENT_SIZE + namlen..];
+        }
+
+        (entries, next)
+    }
+
+    fn unpack_names(buf: &[u8]) -> (Vec<String>, Option<Dircookie>) {
+        let (entries, next) = unpack(buf);
+
+        (entries.into_iter().map(|(v, _)| v).collect(), next)
+    }
+
+    // Lists the directory like wasi-libc's `readdir`, growing the buffer
+    // whenever a single entry does not fit
+    fn list(fd: Fd, buf_len: usize) -> Vec<String> {
+        let mut out = Vec::new();
+        let mut buf = vec![0; buf_len];
+        let mut cookie = DIRCOOKIE_START;
+
+        loop {
+            let n = readdir(fd, cookie, &mut buf);
+            let (got, next) = unpack_names(&buf[..n]);
+            out.extend(got);
+
+            if n < buf.len() {
+                return out;
+            }
+
+            match next {
+                Some(v) if v as i64 == DIRCOOKIE_NEG_ONE => return out,
+                Some(v) => cookie = v,
+                None => buf.resize(buf.len() * 2, 0),
+            }
+        }
+    }
+
+    fn names(n: usize) -> Vec<String> {
+        (0..n)
+            .map(|i| format!("{}{i}", "f".repeat(i % 37)))
+            .collect()
+    }
+
+    #[test]
+    fn packs_entries_back_to_back() {
+        let names = names(3);
+        let root = populate(&names);
+
+        FILESYSTEM.with(|fs| {
+            fs.borrow_mut()
+                .mkdir(root, "dir", FdStat::default(), 0)
+                .unwrap()
+        });
+
+        let mut buf = vec![0xff; 256];
+        let n = readdir(root, DIRCOOKIE_START, &mut buf);
+
+        let len: usize = names.iter().map(|v| DIRENT_SIZE + v.len()).sum();
+        assert_eq!(n, len + DIRENT_SIZE + 3);
+
+        let (got, next) = unpack(&buf[..n]);
+        let mut expected: Vec<_> = names
+            .iter()
+            .map(|v| (v.clone(), FILETYPE_REGULAR_FILE.raw()))
+            .collect();
+        expected.push(("dir".into(), FILETYPE_DIRECTORY.raw()));
+
+        assert_eq!(got, expected);
+        assert_eq!(next, Some(u64::MAX));
+
+        // Padding after d_type is zeroed
+        assert_eq!(&buf[21..24], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn truncates_last_entry() {
+        let names = names(3);
+        let root = populate(&names);
+
+        let first = DIRENT_SIZE + names[0].len();
+
+        for len in [first + 1, first + 10, first + DIRENT_SIZE + 1] {
+            let mut buf = vec![0; len];
+
+            let n = readdir(root, DIRCOOKIE_START, &mut buf);
+            assert_eq!(n, len);
+
+            let (got, next) = unpack_names(&buf);
+            assert_eq!(got, &names[..1]);
+
+            // The rest is read from the cookie of the last whole entry
+            let mut rest = vec![0; 256];
+            let n = readdir(root, next.unwrap(), &mut rest);
+            assert_eq!(unpack_names(&rest[..n]).0, &names[1..]);
+        }
+    }
+
+    #[test]
+    fn resumes_from_cookie() {
+        let names = names(10);
+        let root = populate(&names);
+
+        let mut buf = vec![0; 4 * DIRENT_SIZE + 20];
+        let n = readdir(root, DIRCOOKIE_START, &mut buf);
+        let (first, next) = unpack_names(&buf[..n]);
+
+        let mut buf = vec![0; 1024];
+        let n = readdir(root, next.unwrap(), &mut buf);
+        let (rest, _) = unpack_names(&buf[..n]);
+
+        assert_eq!([first, rest].concat(), names);
+    }
+
+    #[test]
+    fn lists_many_entries_with_small_buffers() {
+        let names = names(500);
+        let root = populate(&names);
+
+        for len in [1, 8, DIRENT_SIZE, DIRENT_SIZE + 1, 40, 64, 100, 257, 4096] {
+            assert_eq!(list(root, len), names, "buffer of {len} bytes");
+        }
+    }
+
+    #[test]
+    fn lists_empty_directory() {
+        let root = populate(&[]);
+        let mut buf = vec![0; 64];
+
+        assert_eq!(readdir(root, DIRCOOKIE_START, &mut buf), 0);
+    }
+
+    #[test]
+    fn ends_at_last_cookie() {
+        let root = populate(&names(2));
+        let mut buf = vec![0; 64];
+
+        assert_eq!(readdir(root, DIRCOOKIE_NEG_ONE as u64, &mut buf), 0);
+    }
+}
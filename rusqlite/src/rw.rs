@@ -1,72 +1,133 @@
-use std::{cell::RefMut, slice::from_raw_parts};
-
-use stable_fs::fs::{DstBuf, FdStat, FileSystem, OpenFlags, SrcBuf};
-use wasi_shim::wasi::Iovec;
-
-pub(crate) fn read_into_vec(mut fs: RefMut<'_, FileSystem>, path: &str) -> Vec<u8> {
-    let _fd = fs
-        .open(
-            3,
-            path,
-            FdStat::default(),
-            OpenFlags::empty(),
-            ic_cdk::api::time(),
-        )
-        .expect("failed to open file");
+use std::cell::RefMut;
+
+use stable_fs::{
+    error::Error as StableFsError,
+    fs::{FdStat, FileSystem, OpenFlags, Whence},
+};
+
+use crate::error::Error;
+
+// File times come from the IC clock, which native tests do not have
+#[cfg(not(test))]
+fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(test)]
+fn now() -> u64 {
+    0
+}
+
+pub(crate) fn write_chunks<I>(
+    mut fs: RefMut<'_, FileSystem>,
+    path: &str,
+    chunks: I,
+) -> Result<u64, Error>
+where
+    I: IntoIterator<Item = Result<Vec<u8>, Error>>,
+{
+    let _fd = fs.open(
+        3,
+        path,
+        FdStat::default(),
+        OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        now(),
+    )?;
 
     let mut fs = scopeguard::guard(fs, |mut fs| {
         let _ = fs.close(_fd);
     });
 
-    let md = fs
-        .metadata(_fd)
-        .expect("failed to get the opened file metadata");
+    let mut size = 0;
+
+    for bs in chunks {
+        let bs = bs?;
 
-    let mut dst = vec![0; md.size as usize];
+        let s = fs.write(_fd, &bs)?;
+        if s as usize != bs.len() {
+            return Err(StableFsError::IOError.into());
+        }
 
-    let mut buf = vec![Iovec {
-        buf: dst.as_mut_ptr(),
-        buf_len: dst.len(),
-    }];
+        size += s;
+    }
+
+    Ok(size)
+}
+
+pub(crate) fn read_range(
+    mut fs: RefMut<'_, FileSystem>,
+    path: &str,
+    offset: u64,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let _fd = fs.open(3, path, FdStat::default(), OpenFlags::empty(), now())?;
+
+    let mut fs = scopeguard::guard(fs, |mut fs| {
+        let _ = fs.close(_fd);
+    });
 
-    let bs: &[DstBuf] = unsafe {
-        from_raw_parts(
-            buf.as_mut_ptr() as *const DstBuf, //
-            buf.len(),                         //
-        )
+    let md = fs.metadata(_fd)?;
+
+    let n = md.size.saturating_sub(offset).min(len as u64) as usize;
+    let mut dst = vec![0; n];
+
+    fs.seek(_fd, offset as i64, Whence::SET)?;
+
+    let s = fs.read(_fd, &mut dst)?;
+    if s as usize != n {
+        return Err(StableFsError::IOError.into());
+    }
+
+    Ok(dst)
+}
+
+pub(crate) fn remove(mut fs: RefMut<'_, FileSystem>, path: &str) -> Result<(), Error> {
+    match fs.remove_file(3, path) {
+        Ok(()) | Err(StableFsError::NoSuchFileOrDirectory) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns the size of `path`, or 0 if it does not exist.
+pub(crate) fn size(mut fs: RefMut<'_, FileSystem>, path: &str) -> Result<u64, Error> {
+    let _fd = match fs.open(3, path, FdStat::default(), OpenFlags::empty(), now()) {
+        Ok(fd) => fd,
+        Err(StableFsError::NoSuchFileOrDirectory) => return Ok(0),
+        Err(err) => return Err(err.into()),
     };
 
-    fs.read_vec(_fd, bs).expect("failed to read file");
+    let fs = scopeguard::guard(fs, |mut fs| {
+        let _ = fs.close(_fd);
+    });
 
-    dst
+    Ok(fs.metadata(_fd)?.size)
 }
 
-pub(crate) fn write_vec(mut fs: RefMut<'_, FileSystem>, path: &str, bs: &mut [u8]) {
-    let _fd = fs
-        .open(
-            3,
-            path,
-            FdStat::default(),
-            OpenFlags::CREATE | OpenFlags::EXCLUSIVE | OpenFlags::TRUNCATE,
-            ic_cdk::api::time(),
-        )
-        .expect("failed to open file");
+pub(crate) fn write_range(
+    mut fs: RefMut<'_, FileSystem>,
+    path: &str,
+    offset: u64,
+    bs: &[u8],
+) -> Result<(), Error> {
+    let _fd = fs.open(3, path, FdStat::default(), OpenFlags::CREATE, now())?;
 
     let mut fs = scopeguard::guard(fs, |mut fs| {
         let _ = fs.close(_fd);
     });
 
-    let mut buf = [Iovec {
-        buf: bs.as_mut_ptr(),
-        buf_len: bs.len(),
-    }];
+    fs.seek(_fd, offset as i64, Whence::SET)?;
 
-    let bs: &[SrcBuf] = unsafe {
-        from_raw_parts(
-            buf.as_mut_ptr() as *const SrcBuf, //
-            buf.len(),                         //
-        )
-    };
+    let s = fs.write(_fd, bs)?;
+    if s as usize != bs.len() {
+        return Err(StableFsError::IOError.into());
+    }
+
+    Ok(())
+}
+
+pub(crate) fn rename(mut fs: RefMut<'_, FileSystem>, from: &str, to: &str) -> Result<(), Error> {
+    let _fd = fs.rename(3, from, 3, to)?;
+    let _ = fs.close(_fd);
 
-    fs.write_vec(_fd, bs).expect("failed to write");
+    Ok(())
 }
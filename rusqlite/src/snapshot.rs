@@ -0,0 +1,123 @@
+use std::cell::Cell;
+
+use candid::CandidType;
+use serde::Deserialize;
+use stable_fs::error::Error as StableFsError;
+
+use crate::{
+    checksum::Checksum,
+    error::Error,
+    rw::{read_range, size},
+    FILESYSTEM,
+};
+
+// Position of the file change counter in the SQLite header
+const CHANGE_COUNTER_OFFSET: u64 = 24;
+
+thread_local! {
+    // Commits made through `CONN` since the canister was started
+    static COMMITS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Commit hook installed on `CONN`, returning `false` lets the commit proceed.
+pub(crate) fn commit_hook() -> bool {
+    COMMITS.with(|c| c.set(c.get() + 1));
+
+    false
+}
+
+/// Copy of a database file taken a few chunks at a time, across as many
+/// messages as it needs. It starts over whenever the file changed between two
+/// steps, so the chunks handed out always add up to one consistent file.
+///
+/// Commits are only counted on the heap, so a snapshot must not be resumed
+/// after an upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub(crate) struct Snapshot {
+    pub(crate) size: u64,
+    pub(crate) copied: u64,
+    version: Version,
+    checksum: Checksum,
+}
+
+// Commits through `CONN` catch regular writes, the change counter in the
+// header anything else that rewrites the file, such as VACUUM
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Version {
+    commits: u64,
+    change_counter: Vec<u8>,
+    size: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn start(path: &str) -> Result<Self, Error> {
+        let version = version(path)?;
+
+        Ok(Self {
+            size: version.size,
+            copied: 0,
+            version,
+            checksum: Checksum::default(),
+        })
+    }
+
+    pub(crate) fn done(&self) -> bool {
+        self.copied >= self.size
+    }
+
+    /// Copies up to `max_chunks` chunks of `chunk_size` bytes, passing each to
+    /// `f` along with its offset. If the file changed since the last step, the
+    /// copy starts over from offset 0.
+    pub(crate) fn step<F>(
+        &mut self,
+        path: &str,
+        chunk_size: usize,
+        max_chunks: u64,
+        mut f: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64, &[u8]) -> Result<(), Error>,
+    {
+        if version(path)? != self.version {
+            *self = Self::start(path)?;
+        }
+
+        for _ in 0..max_chunks {
+            if self.done() {
+                break;
+            }
+
+            let bs =
+                FILESYSTEM.with(|fs| read_range(fs.borrow_mut(), path, self.copied, chunk_size))?;
+
+            if bs.is_empty() {
+                return Err(StableFsError::IOError.into());
+            }
+
+            f(self.copied, &bs)?;
+
+            self.checksum.update(&bs);
+            self.copied += bs.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    /// SHA-256 of the whole file, once the copy is `done`.
+    pub(crate) fn sha256(&self) -> Vec<u8> {
+        self.checksum.finalize()
+    }
+}
+
+fn version(path: &str) -> Result<Version, Error> {
+    let size = FILESYSTEM.with(|fs| size(fs.borrow_mut(), path))?;
+
+    let change_counter =
+        FILESYSTEM.with(|fs| read_range(fs.borrow_mut(), path, CHANGE_COUNTER_OFFSET, 4))?;
+
+    Ok(Version {
+        commits: COMMITS.with(|c| c.get()),
+        change_counter,
+        size,
+    })
+}
@@ -0,0 +1,126 @@
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
+};
+
+use candid::CandidType;
+use serde::Deserialize;
+
+// Bounds the heap used by stats when clients send many distinct statements
+const MAX_STATEMENTS: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct StatementStats {
+    pub sql: String,
+    pub calls: u64,
+
+    /// Calls that returned an error, included in `calls`.
+    pub failures: u64,
+    pub total_instructions: u64,
+    pub max_instructions: u64,
+
+    /// Rows changed by successful writes, or returned by successful reads.
+    pub rows: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    by_sql: HashMap<String, StatementStats>,
+
+    // Call count and SQL of every entry, ordered to find the least called one
+    by_calls: BTreeSet<(u64, String)>,
+}
+
+impl Stats {
+    fn add(&mut self, sql: &str, used: u64, changes: Option<u64>) {
+        if !self.by_sql.contains_key(sql) && self.by_sql.len() >= MAX_STATEMENTS {
+            // Makes room by dropping the statement called least often
+            if let Some((_, k)) = self.by_calls.pop_first() {
+                self.by_sql.remove(&k);
+            }
+        }
+
+        let s = self
+            .by_sql
+            .entry(sql.to_owned())
+            .or_insert_with(|| StatementStats {
+                sql: sql.to_owned(),
+                ..Default::default()
+            });
+
+        self.by_calls.remove(&(s.calls, sql.to_owned()));
+
+        s.calls += 1;
+        s.total_instructions += used;
+        s.max_instructions = s.max_instructions.max(used);
+
+        match changes {
+            Some(n) => s.rows += n,
+            None => s.failures += 1,
+        }
+
+        self.by_calls.insert((s.calls, sql.to_owned()));
+    }
+}
+
+thread_local! {
+    static STATS: RefCell<Stats> = RefCell::new(Stats::default());
+}
+
+/// Instruction counter at the start of a statement, passed to `record`.
+pub(crate) struct Start(u64);
+
+pub(crate) fn start() -> Start {
+    Start(ic_cdk::api::performance_counter(0))
+}
+
+/// Adds a call of `sql` that began at `start`, with the rows it changed or
+/// returned, or `None` if it failed.
+///
+/// State changes made by query calls are discarded, so reads only show up
+/// when `query` or `run_named` is called as an update.
+pub(crate) fn record(sql: &str, start: Start, changes: Option<u64>) {
+    let used = ic_cdk::api::performance_counter(0).saturating_sub(start.0);
+
+    STATS.with(|m| m.borrow_mut().add(sql, used, changes));
+}
+
+/// Returns the stats of every recorded statement, most expensive first.
+pub(crate) fn list() -> Vec<StatementStats> {
+    let mut out: Vec<_> = STATS.with(|m| m.borrow().by_sql.values().cloned().collect());
+
+    out.sort_by_key(|s| Reverse(s.total_instructions));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_called() {
+        let mut st = Stats::default();
+
+        // Every statement is called twice, except s7
+        for i in 0..MAX_STATEMENTS {
+            st.add(&format!("s{i}"), 10, Some(1));
+
+            if i != 7 {
+                st.add(&format!("s{i}"), 10, None);
+            }
+        }
+
+        st.add("new", 5, Some(2));
+
+        assert_eq!(st.by_sql.len(), MAX_STATEMENTS);
+        assert_eq!(st.by_calls.len(), MAX_STATEMENTS);
+        assert!(!st.by_sql.contains_key("s7"));
+
+        let s = &st.by_sql["s1"];
+        assert_eq!(
+            (s.calls, s.failures, s.rows, s.total_instructions),
+            (2, 1, 1, 20)
+        );
+    }
+}
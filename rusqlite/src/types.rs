@@ -0,0 +1,77 @@
+use candid::CandidType;
+use rusqlite::types::{Value, ValueRef};
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+impl From<SqlValue> for Value {
+    fn from(v: SqlValue) -> Self {
+        match v {
+            SqlValue::Null => Value::Null,
+            SqlValue::Integer(v) => Value::Integer(v),
+            SqlValue::Real(v) => Value::Real(v),
+            SqlValue::Text(v) => Value::Text(v),
+            SqlValue::Blob(v) => Value::Blob(v),
+        }
+    }
+}
+
+impl From<ValueRef<'_>> for SqlValue {
+    fn from(v: ValueRef<'_>) -> Self {
+        match v {
+            ValueRef::Null => SqlValue::Null,
+            ValueRef::Integer(v) => SqlValue::Integer(v),
+            ValueRef::Real(v) => SqlValue::Real(v),
+            ValueRef::Text(v) => SqlValue::Text(String::from_utf8_lossy(v).into_owned()),
+            ValueRef::Blob(v) => SqlValue::Blob(v.to_vec()),
+        }
+    }
+}
+
+pub type SqlRow = Vec<SqlValue>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Column {
+    pub name: String,
+    pub decl_type: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct QueryResult {
+    pub columns: Vec<Column>,
+    pub rows: Vec<SqlRow>,
+
+    /// Passed back in `Page::cursor` to fetch the rows after this page.
+    pub next: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Page {
+    pub cursor: Option<Vec<u8>>,
+    pub max_rows: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExecuteResult {
+    pub changes: u64,
+    pub last_insert_rowid: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SchemaVersion {
+    pub current: u32,
+    pub target: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Statement {
+    pub sql: String,
+    pub params: Vec<SqlValue>,
+}
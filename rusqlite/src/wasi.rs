@@ -1,6 +1,10 @@
 use crate::polyfill;
 
 pub(crate) fn inject_shims() {
+    rng::inject_shim();
+
+    polyfill::hook_panics();
+
     unsafe {
         wasi_shim::core::args::set::args_get(polyfill::args_get);
         wasi_shim::core::args::set::args_sizes_get(polyfill::args_sizes_get);
@@ -42,7 +46,6 @@ pub(crate) fn inject_shims() {
         wasi_shim::core::poll::set::poll_oneoff(polyfill::poll_oneoff);
         wasi_shim::core::proc::set::proc_exit(polyfill::proc_exit);
         wasi_shim::core::proc::set::proc_raise(polyfill::proc_raise);
-        wasi_shim::core::random::set::random_get(polyfill::random_get);
         wasi_shim::core::sched::set::sched_yield(polyfill::sched_yield);
         wasi_shim::core::sock::set::sock_accept(polyfill::sock_accept);
         wasi_shim::core::sock::set::sock_recv(polyfill::sock_recv);